@@ -0,0 +1,349 @@
+// Validates a configuration file, reporting all problems at once with their position in the file
+
+use std::fs::File;
+use std::io::Read;
+use encoding_rs::Encoding;
+use regex::Regex;
+use strsim::damerau_levenshtein;
+use yaml_rust2::Yaml;
+use yaml_rust2::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust2::scanner::{Marker, TScalarStyle};
+use crate::{lookup_binary_type, BBox, BinaryType};
+
+const OPTIONS: [&str; 23] = ["name", "path", "file", "cols", "emit", "hush", "mode", "skip", "prog", "zip", "encoding", "namespaces", "format", "delimiter", "header", "connection", "fail", "rejects", "tables", "spill", "state", "checkpoint", "interval"];
+const TABLE_OPTIONS: [&str; 5] = ["name", "path", "file", "cols", "skip"]; // Options of the entries in 'tables'; the others apply to all of them
+const COLUMN_OPTIONS: [&str; 25] = ["name", "path", "file", "norm", "dict", "hash", "cols", "seri", "fkey", "pkey", "type", "attr", "hide", "trim", "incl", "excl", "find", "repl", "conv", "aggr", "bbox", "mult", "fail", "form", "strict"];
+
+struct Node {
+  value: Yaml, // Empty Hash or Array for mappings and sequences
+  mark: Marker,
+  map: Vec<(String, Marker, Node)>,
+  seq: Vec<Node>
+}
+impl Node {
+  fn get(&self, key: &str) -> Option<&Node> {
+    self.map.iter().find(|(k, _, _)| k == key).map(|(_, _, node)| node)
+  }
+}
+
+#[derive(Default)]
+struct Builder {
+  stack: Vec<(Node, Option<(String, Marker)>)>, // Open mappings and sequences, with the pending key for mappings
+  root: Option<Node>
+}
+impl Builder {
+  fn add(&mut self, node: Node, raw: Option<String>) {
+    match self.stack.last_mut() {
+      None => self.root = Some(node),
+      Some((parent, _)) if parent.value.is_array() => parent.seq.push(node),
+      Some((parent, key)) => match key.take() {
+        Some((key, mark)) => parent.map.push((key, mark, node)),
+        None => {
+          if parent.map.is_empty() { parent.mark = node.mark; } // Report mappings at their first key
+          *key = Some((raw.unwrap_or_default(), node.mark));
+        }
+      }
+    }
+  }
+}
+impl MarkedEventReceiver for Builder {
+  fn on_event(&mut self, event: Event, mark: Marker) {
+    let container = |value| Node { value, mark, map: Vec::new(), seq: Vec::new() };
+    match event {
+      Event::MappingStart(..) => self.stack.push((container(Yaml::Hash(Default::default())), None)),
+      Event::SequenceStart(..) => self.stack.push((container(Yaml::Array(Vec::new())), None)),
+      Event::MappingEnd | Event::SequenceEnd => {
+        let (node, _) = self.stack.pop().unwrap();
+        self.add(node, None);
+      },
+      Event::Scalar(value, style, ..) => {
+        let yaml = if style == TScalarStyle::Plain { Yaml::from_str(&value) } else { Yaml::String(value.clone()) };
+        self.add(container(yaml), Some(value));
+      },
+      Event::Alias(_) => self.add(container(Yaml::Null), None),
+      _ => ()
+    }
+  }
+}
+
+#[derive(Default)]
+struct Checker {
+  problems: Vec<(usize, usize, &'static str, String)>,
+  binary: bool,
+  append: bool
+}
+impl Checker {
+  fn error(&mut self, mark: Marker, msg: String) {
+    self.problems.push((mark.line(), mark.col()+1, "Error", msg));
+  }
+  fn warning(&mut self, mark: Marker, msg: String) {
+    self.problems.push((mark.line(), mark.col()+1, "Warning", msg));
+  }
+  fn keys(&mut self, node: &Node, known: &[&str], other: &[&str], context: &str) {
+    for (key, mark, _) in &node.map {
+      if known.contains(&key.as_str()) { continue; }
+      if other.contains(&key.as_str()) {
+        self.error(*mark, format!("option '{}' is not valid {}", key, context));
+        continue;
+      }
+      match known.iter().map(|k| (damerau_levenshtein(k, key), k)).min() {
+        Some((distance, suggestion)) if distance <= 2 && distance < key.len() => self.error(*mark, format!("unknown option '{}' {}; did you mean '{}'?", key, context, suggestion)),
+        _ => self.error(*mark, format!("unknown option '{}' {}", key, context))
+      }
+    }
+  }
+  fn string<'a>(&mut self, node: &'a Node, key: &str, required: bool, context: &str) -> Option<&'a str> {
+    match node.get(key) {
+      Some(value) => match value.value.as_str() {
+        Some(str) => Some(str),
+        None => {
+          self.error(value.mark, format!("option '{}' {} should be a string", key, context));
+          None
+        }
+      },
+      None => {
+        if required { self.error(node.mark, format!("no '{}' entry {}", key, context)); }
+        None
+      }
+    }
+  }
+  fn boolean(&mut self, node: &Node, key: &str, context: &str) -> Option<bool> {
+    let value = node.get(key)?;
+    if value.value.as_bool().is_none() { self.error(value.mark, format!("option '{}' {} should be true or false", key, context)); }
+    value.value.as_bool()
+  }
+  fn regex(&mut self, node: &Node, key: &str, context: &str) {
+    if let Some(str) = self.string(node, key, false, context) {
+      if let Err(err) = Regex::new(str) { self.error(node.get(key).unwrap().mark, format!("invalid regex in '{}' entry {}: {}", key, context, err)); }
+    }
+  }
+  fn choice(&mut self, node: &Node, key: &str, values: &[&str], context: &str) -> Option<String> {
+    let str = self.string(node, key, false, context)?;
+    if !values.contains(&str) { self.error(node.get(key).unwrap().mark, format!("option '{}' {} contains invalid value '{}'; should be one of {}", key, context, str, values.join(", "))); }
+    Some(str.to_owned())
+  }
+  fn mismatch(&mut self, node: &Node, context: &str) {
+    if node.get("fail").is_some_and(|fail| fail.value.is_null()) { return; } // An unquoted null
+    self.choice(node, "fail", &["error", "null", "skip"], context);
+  }
+  fn tokens(&mut self, node: &Node, key: &str, values: &[&str]) { // Options that take a list of words, like 'emit' and 'hush'
+    let Some(str) = self.string(node, key, false, "") else { return };
+    for token in str.split(|c: char| !c.is_ascii_alphanumeric() && c != '_').filter(|t| !t.is_empty()) {
+      if !values.iter().any(|v| token.starts_with(v)) {
+        self.error(node.get(key).unwrap().mark, format!("option '{}' contains invalid value '{}'; valid values are {}", key, token, values.join(", ")));
+      }
+    }
+  }
+
+  fn config(&mut self, root: &Node) {
+    if !root.value.is_hash() {
+      self.error(root.mark, String::from("the configuration should be a mapping of options"));
+      return;
+    }
+    self.keys(root, &OPTIONS, &COLUMN_OPTIONS, "at the top level");
+    for key in ["zip", "connection", "rejects", "state", "checkpoint"] { self.string(root, key, false, "at the top level"); }
+    self.boolean(root, "prog", "at the top level");
+    self.tokens(root, "emit", &["copy_from", "create_table", "start_trans", "truncate", "drop_table", "identity"]);
+    self.tokens(root, "hush", &["version", "info", "notice", "warn"]);
+    self.append = self.choice(root, "mode", &["truncate", "append"], "at the top level").as_deref() == Some("append");
+    self.mismatch(root, "at the top level");
+    if let Some(spill) = root.get("spill") {
+      if spill.value.as_i64().is_none_or(|n| n <= 0) { self.error(spill.mark, String::from("option 'spill' should be a positive number of values")); }
+    }
+    if let Some(interval) = root.get("interval") {
+      if interval.value.as_i64().is_none_or(|n| n <= 0) { self.error(interval.mark, String::from("option 'interval' should be a positive number of seconds")); }
+      else if root.get("checkpoint").is_none() { self.warning(interval.mark, String::from("option 'interval' has no function without 'checkpoint'")); }
+    }
+    if let (Some(checkpoint), Some(_)) = (root.get("checkpoint"), root.get("connection")) {
+      self.error(checkpoint.mark, String::from("the 'checkpoint' option cannot be used together with 'connection'"));
+    }
+    if let Some(label) = self.string(root, "encoding", false, "at the top level") {
+      if Encoding::for_label(label.as_bytes()).is_none() { self.error(root.get("encoding").unwrap().mark, format!("unknown encoding '{}'", label)); }
+    }
+    if let Some(namespaces) = root.get("namespaces") {
+      match namespaces.value.as_str() {
+        Some("ignore") => (),
+        _ if namespaces.value.is_hash() => for (prefix, mark, uri) in &namespaces.map {
+          if uri.value.as_str().is_none() { self.error(*mark, format!("namespace prefix '{}' should map to a URI", prefix)); }
+        },
+        _ => self.error(namespaces.mark, String::from("option 'namespaces' should be a map of prefixes to URIs or 'ignore'"))
+      }
+    }
+    let format = self.choice(root, "format", &["text", "csv", "binary"], "at the top level");
+    if let Some(delimiter) = self.string(root, "delimiter", false, "at the top level") {
+      let mark = root.get("delimiter").unwrap().mark;
+      if format.as_deref() != Some("csv") { self.error(mark, String::from("option 'delimiter' can only be used with format csv")); }
+      else if delimiter.len() != 1 || delimiter.contains(['"', '\n', '\r']) { self.error(mark, format!("invalid delimiter '{}'; should be a single character", delimiter)); }
+    }
+    if self.boolean(root, "header", "at the top level").is_some() && format.as_deref() != Some("csv") {
+      self.warning(root.get("header").unwrap().mark, String::from("option 'header' has no function without format csv"));
+    }
+    self.binary = format.as_deref() == Some("binary");
+    if self.binary && root.get("emit").is_some() && root.get("connection").is_none() {
+      self.error(root.get("emit").unwrap().mark, String::from("the 'emit' option cannot be used with format binary"));
+    }
+    match root.get("tables") {
+      None => self.table(root, "at the top level"),
+      Some(tables) if tables.value.is_array() => {
+        for key in TABLE_OPTIONS {
+          if let Some(value) = root.get(key) { self.error(value.mark, format!("option '{}' belongs in the entries of 'tables' when that is used", key)); }
+        }
+        let mut stdout = None; // The table without a 'file', whose output goes to stdout
+        for (i, table) in tables.seq.iter().enumerate() {
+          if !table.value.is_hash() {
+            self.error(table.mark, format!("table {} should be a mapping of options", i+1));
+            continue;
+          }
+          let context = format!("in table {}", i+1);
+          self.keys(table, &TABLE_OPTIONS, &OPTIONS, &context);
+          self.table(table, &context);
+          if table.get("file").is_none() && root.get("connection").is_none() {
+            match stdout {
+              Some(first) => self.error(table.mark, format!("table {} has no 'file', like table {}; only one of the tables can be written to stdout", i+1, first)),
+              None => stdout = Some(i+1)
+            }
+          }
+        }
+      },
+      Some(tables) => self.error(tables.mark, String::from("option 'tables' should be a list of tables"))
+    }
+  }
+  fn table(&mut self, table: &Node, context: &str) {
+    let name = self.string(table, "name", true, context).unwrap_or("main");
+    self.string(table, "path", true, context);
+    for key in ["file", "skip"] { self.string(table, key, false, context); }
+    match table.get("cols") {
+      Some(cols) if cols.value.is_array() => self.columns(name, cols),
+      Some(cols) => self.error(cols.mark, String::from("option 'cols' should be an array of columns")),
+      None => self.error(table.mark, format!("no 'cols' entry {}", context))
+    }
+  }
+  fn columns(&mut self, table: &str, cols: &Node) {
+    let mut subtable = None; // The first subtable column; key columns should precede it
+    for (i, col) in cols.seq.iter().enumerate() {
+      if !col.value.is_hash() {
+        self.error(col.mark, format!("column {} of table '{}' should be a mapping of options", i+1, table));
+        continue;
+      }
+      let context = format!("in table '{}' column {}", table, i+1);
+      self.keys(col, &COLUMN_OPTIONS, &OPTIONS, &context);
+      let name = self.string(col, "name", true, &context).unwrap_or("?");
+      let context = format!("in table '{}' column '{}'", table, name);
+      let seri = match col.get("seri") {
+        None => false,
+        Some(seri) if seri.value.as_bool().is_some() => seri.value.as_bool().unwrap(),
+        Some(seri) if seri.value.as_i64().is_some_and(|n| n >= 0) => true,
+        Some(seri) => {
+          self.error(seri.mark, format!("option 'seri' {} should be true or the number to continue numbering after", context));
+          false
+        }
+      };
+      if seri && i > 0 { self.warning(col.mark, format!("a 'seri' column usually needs to be the first column; {} in table {} is not", name, table)); }
+      let fkey = self.string(col, "fkey", false, &context);
+      if fkey.is_some_and(|fkey| !fkey.contains('.')) { self.error(col.get("fkey").unwrap().mark, format!("option 'fkey' {} should be in the form table.column", context)); }
+      self.string(col, "path", !seri && fkey.is_none(), &context);
+      for key in ["attr", "repl", "file"] { self.string(col, key, false, &context); }
+      for key in ["hide", "trim", "mult"] { self.boolean(col, key, &context); }
+      if self.boolean(col, "pkey", &context) == Some(true) {
+        let mark = col.get("pkey").unwrap().mark;
+        if col.get("cols").is_some() { self.error(mark, format!("subtable {} cannot be part of the key of table '{}'", name, table)); }
+        else if let Some(subtable) = subtable { self.error(mark, format!("key column {} of table '{}' should come before subtable {}", name, table, subtable)); }
+      }
+      if self.boolean(col, "strict", &context).is_some() && col.get("incl").is_none() && col.get("excl").is_none() {
+        self.warning(col.get("strict").unwrap().mark, format!("option 'strict' {} has no function without filtering (incl/excl)", context));
+      }
+      for key in ["incl", "excl", "find"] { self.regex(col, key, &context); }
+      if col.get("repl").is_some() && col.get("find").is_none() { self.warning(col.get("repl").unwrap().mark, format!("option 'repl' {} has no function without 'find'", context)); }
+      let convert = self.choice(col, "conv", &["xml-to-text", "gml-to-ewkb", "concat-text"], &context);
+      self.choice(col, "aggr", &["first", "last", "append"], &context);
+      if (col.get("incl").is_some() || col.get("excl").is_some()) && convert.is_some() {
+        self.error(col.get("conv").unwrap().mark, format!("filtering (incl/excl) and 'conv' cannot be used together {}", context));
+      }
+      if let Some(bbox) = self.string(col, "bbox", false, &context) {
+        let mark = col.get("bbox").unwrap().mark;
+        if BBox::from(bbox).is_none() { self.error(mark, format!("invalid 'bbox' {}; should be in the form 'minx,miny maxx,maxy'", context)); }
+        if convert.as_deref() != Some("gml-to-ewkb") { self.warning(mark, String::from("the bbox option has no function without conversion type 'gml-to-ewkb'")); }
+      }
+      self.mismatch(col, &context);
+      let datatype = self.string(col, "type", false, &context);
+      if let Some(form) = col.get("form") {
+        if form.value.as_str().is_none() && !(form.value.is_array() && form.seq.iter().all(|f| f.value.as_str().is_some())) {
+          self.error(form.mark, format!("option 'form' {} should be a format string or a list of them", context));
+        }
+        if !datatype.and_then(lookup_binary_type).is_some_and(|t| matches!(t, BinaryType::Date | BinaryType::Timestamp | BinaryType::TimestampTz)) {
+          self.warning(form.mark, format!("option 'form' {} has no function without a date or timestamp type", context));
+        }
+      }
+      if let Some(datatype) = datatype {
+        if self.binary && col.get("norm").is_none() && lookup_binary_type(datatype).is_none() {
+          self.error(col.get("type").unwrap().mark, format!("type '{}' {} is not supported with format binary", datatype, context));
+        }
+      }
+      let norm = self.string(col, "norm", false, &context);
+      if norm == Some("true") || col.get("norm").is_some_and(|n| n.value.as_bool().is_some()) {
+        self.error(col.get("norm").unwrap().mark, String::from("option 'norm' now takes a file path instead of a boolean"));
+      }
+      match self.string(col, "dict", false, &context) {
+        Some(_) if col.get("norm").is_none() => self.warning(col.get("dict").unwrap().mark, format!("option 'dict' {} has no function without 'norm'", context)),
+        Some(_) if self.binary => self.error(col.get("dict").unwrap().mark, format!("option 'dict' {} cannot be used with format binary", context)),
+        Some(dict) if norm == Some(dict) && !self.append => self.error(col.get("dict").unwrap().mark, format!("option 'dict' {} is the 'norm' file, which would be overwritten before it is read; use 'mode: append' or a copy of the file", context)),
+        _ => ()
+      }
+      if let Some(hash) = col.get("hash") {
+        if seri {
+          let names: Vec<&str> = match hash.value.as_str() {
+            Some(name) => vec![name],
+            None => hash.seq.iter().filter_map(|h| h.value.as_str()).collect()
+          };
+          if names.is_empty() || (hash.value.is_array() && names.len() != hash.seq.len()) { self.error(hash.mark, format!("option 'hash' {} should be a column name or a list of them", context)); }
+          for source in names {
+            if !cols.seq.iter().any(|c| c.get("name").and_then(|n| n.value.as_str()) == Some(source) && c.get("cols").is_none() && c.get("seri").is_none_or(|s| s.value.as_bool() == Some(false))) {
+              self.error(hash.mark, format!("option 'hash' {} refers to '{}', which is not a value column of table '{}'", context, source, table));
+            }
+          }
+        }
+        else if norm.is_some() && col.get("cols").is_none() {
+          if hash.value.as_bool().is_none() { self.error(hash.mark, format!("option 'hash' {} should be true or false", context)); }
+        }
+        else { self.error(hash.mark, format!("option 'hash' {} needs 'seri' with the columns to hash, or 'norm' with true", context)); }
+      }
+      let file = col.get("file");
+      if file.is_some() && i == 0 { self.error(col.mark, format!("table '{}' cannot have a subtable as first column", table)); }
+      if (file.is_some() || col.get("cols").is_some()) && subtable.is_none() { subtable = Some(name); }
+      match col.get("cols") {
+        Some(subcols) if subcols.value.is_array() => {
+          if file.is_none() && norm.is_none() { self.error(col.mark, format!("subtable {} has no 'file' entry", name)); }
+          if file.is_none() && subcols.seq.iter().filter(|c| c.get("pkey").is_some_and(|k| k.value.as_bool() == Some(true))).count() > 1 {
+            self.error(col.mark, format!("subtable {} is normalized into table '{}', so it cannot have a key of multiple columns; use a 'seri' column instead", name, table));
+          }
+          self.columns(name, subcols);
+        },
+        Some(subcols) => self.error(subcols.mark, format!("option 'cols' {} should be an array of columns", context)),
+        None => ()
+      }
+    }
+  }
+}
+
+pub fn check(configfile: &str) -> bool {
+  let mut config_str = String::new();
+  let mut file = File::open(configfile).unwrap_or_else(|err| fatalerr!("Error: failed to open configuration file '{}': {}", configfile, err));
+  file.read_to_string(&mut config_str).unwrap_or_else(|err| fatalerr!("Error: failed to read configuration file '{}': {}", configfile, err));
+  let mut builder = Builder::default();
+  if let Err(err) = Parser::new_from_str(&config_str).load(&mut builder, false) {
+    eprintln!("Error: line {}, column {}: invalid syntax in configuration file: {}", err.marker().line(), err.marker().col()+1, err.info());
+    return false;
+  }
+  let mut checker = Checker::default();
+  match builder.root {
+    Some(root) => checker.config(&root),
+    None => checker.problems.push((1, 1, "Error", String::from("the configuration file is empty")))
+  }
+  checker.problems.sort_by_key(|(line, col, _, _)| (*line, *col));
+  for (line, col, level, msg) in &checker.problems {
+    eprintln!("{}: line {}, column {}: {}", level, line, col, msg);
+  }
+  let errors = checker.problems.iter().filter(|(_, _, level, _)| *level == "Error").count();
+  eprintln!("Info: {} has {} errors and {} warnings", configfile, errors, checker.problems.len()-errors);
+  errors == 0
+}
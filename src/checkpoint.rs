@@ -0,0 +1,246 @@
+// Periodic checkpoints of a conversion, so that an interrupted run can continue where it left off with --resume
+
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Lines};
+use std::mem;
+use std::time::{Duration, Instant};
+use crate::diskmap::DiskMap;
+use crate::{unescape_text, Cardinality, Domain, Settings, State, Table};
+
+const HEADER: &str = "xml-to-postgres checkpoint";
+
+pub struct Checkpoint {
+  file: String,
+  interval: Duration,
+  last: Instant,
+  generation: u64, // Numbers the copies of the domain indexes, so those of the previous checkpoint stay intact until it is replaced
+  indexes: Vec<String> // Copies of the domain indexes that belong to the current checkpoint
+}
+impl Checkpoint {
+  pub fn new(file: &str, interval: u64, resume: Option<&Resume>) -> Checkpoint {
+    let (generation, indexes) = resume.map_or((0, Vec::new()), |resume| (resume.generation+1, resume.indexes.clone()));
+    Checkpoint { file: file.to_owned(), interval: Duration::from_secs(interval), last: Instant::now(), generation, indexes }
+  }
+  pub fn due(&self) -> bool {
+    self.last.elapsed() >= self.interval
+  }
+  pub fn write(&mut self, state: &State, input: usize, startcount: u64, filecounts: &[(String, u64, u64)]) { // Only at a row boundary, with all rows written out
+    for table in state.maintables {
+      visit(table, &mut |node| match node {
+        Node::Table(table) => table.sync(),
+        Node::Domain(domain) => domain.borrow().table.sync()
+      });
+    }
+    let mut out = String::new();
+    writeln!(out, "{}", HEADER).unwrap();
+    writeln!(out, "input\t{}\t{}\t{}\t{}", input, escape(&state.filename), state.position(), startcount).unwrap();
+    writeln!(out, "generation\t{}", self.generation).unwrap();
+    writeln!(out, "path\t{}", escape(&state.path)).unwrap();
+    writeln!(out, "main\t{}", state.maintables.iter().position(|t| std::ptr::eq(t, state.table)).unwrap()).unwrap();
+    writeln!(out, "counts\t{}\t{}\t{}\t{}\t{}\t{}", state.fullcount, state.filtercount, state.skipcount, state.rejectcount, state.nullcount, state.quarantined).unwrap();
+    for (depth, prefix, uri) in &state.nsbindings { writeln!(out, "ns\t{}\t{}\t{}", depth, escape(prefix), escape(uri)).unwrap(); }
+    for (file, rows, bytes) in filecounts { writeln!(out, "file\t{}\t{}\t{}", escape(file), rows, bytes).unwrap(); }
+    if let Some(rejects) = &state.rejects {
+      writeln!(out, "rejects\t{}", rejects.metadata().unwrap_or_else(|err| fatalerr!("Error: failed to read length of rejects file: {}", err)).len()).unwrap();
+    }
+    let mut indexes = Vec::new();
+    let prefix = format!("{}.{}", self.file, self.generation);
+    for table in state.maintables {
+      visit(table, &mut |node| match node {
+        Node::Table(table) => {
+          writeln!(out, "table\t{}", table_record(table)).unwrap();
+          for col in &table.columns {
+            if let Some(hash) = &col.hash { map_record(&mut out, &hash.seen.borrow(), &prefix, &mut indexes); }
+          }
+        },
+        Node::Domain(domain) => {
+          let domain = domain.borrow();
+          writeln!(out, "domain\t{}\t{}", domain.lastid, table_record(&domain.table)).unwrap();
+          map_record(&mut out, &domain.map, &prefix, &mut indexes);
+          if let Some(hash) = &domain.hash { map_record(&mut out, &hash.seen.borrow(), &prefix, &mut indexes); }
+        }
+      });
+    }
+    let temp = format!("{}.tmp", self.file); // Replaces the previous checkpoint only once complete
+    fs::write(&temp, out).and_then(|_| fs::rename(&temp, &self.file)).unwrap_or_else(|err| fatalerr!("Error: failed to write checkpoint file '{}': {}", self.file, err));
+    remove_indexes(&mem::replace(&mut self.indexes, indexes));
+    self.generation += 1;
+    self.last = Instant::now();
+  }
+  pub fn remove(self) { // The run is complete
+    let _ = fs::remove_file(&self.file);
+    remove_indexes(&self.indexes);
+  }
+}
+
+pub struct Resume {
+  pub input: usize, // Number of inputs completed before the checkpoint
+  pub filename: String,
+  pub position: u64, // Byte offset in the (decompressed) input
+  pub startcount: u64, // Row count at the start of the input
+  pub path: String,
+  pub main: usize,
+  pub counts: [u64; 6],
+  pub nsbindings: Vec<(usize, String, String)>,
+  pub filecounts: Vec<(String, u64, u64)>,
+  pub rejects: Option<u64>,
+  generation: u64,
+  indexes: Vec<String>, // Copies of the domain indexes the checkpoint refers to
+  lines: Lines<BufReader<File>>, // The tables and domains follow the fields above, in the order they are visited
+  next: Option<Vec<String>> // Record read ahead
+}
+impl Resume {
+  pub fn load(file: &str) -> Resume {
+    let mut lines = File::open(file).map(|f| BufReader::new(f).lines()).unwrap_or_else(|err| fatalerr!("Error: failed to read checkpoint file '{}': {}", file, err));
+    if !lines.next().is_some_and(|line| line.is_ok_and(|line| line == HEADER)) { fatalerr!("Error: '{}' is not a checkpoint file", file); }
+    let invalid = || -> ! { fatalerr!("Error: checkpoint file '{}' is invalid", file) };
+    let number = |field: &str| field.parse::<u64>().unwrap_or_else(|_| invalid());
+    let mut resume = Resume { input: 0, filename: String::new(), position: 0, startcount: 0, path: String::new(), main: 0, counts: [0; 6], nsbindings: Vec::new(), filecounts: Vec::new(), rejects: None, generation: 0, indexes: Vec::new(), lines, next: None };
+    while resume.peek(file).is_some_and(|fields| !["table", "domain", "index", "values", "value"].contains(&fields[0].as_str())) {
+      let fields = resume.read(file).unwrap();
+      match (fields[0].as_str(), fields.len()) {
+        ("input", 5) => {
+          resume.input = number(&fields[1]) as usize;
+          resume.filename = fields[2].clone();
+          resume.position = number(&fields[3]);
+          resume.startcount = number(&fields[4]);
+        },
+        ("path", 2) => resume.path = fields[1].clone(),
+        ("main", 2) => resume.main = number(&fields[1]) as usize,
+        ("counts", 7) => for i in 0..6 { resume.counts[i] = number(&fields[i+1]); },
+        ("ns", 4) => resume.nsbindings.push((number(&fields[1]) as usize, fields[2].clone(), fields[3].clone())),
+        ("file", 4) => resume.filecounts.push((fields[1].clone(), number(&fields[2]), number(&fields[3]))),
+        ("rejects", 2) => resume.rejects = Some(number(&fields[1])),
+        ("generation", 2) => resume.generation = number(&fields[1]),
+        _ => invalid()
+      }
+    }
+    if resume.filename.is_empty() { invalid(); }
+    resume
+  }
+  pub fn restore(&mut self, maintables: &[Table], file: &str, settings: &Settings) { // Sets the counters and domains of the tables and truncates their output
+    for table in maintables {
+      visit(table, &mut |node| match node {
+        Node::Table(table) => {
+          let record = self.next_record("table", &table.name, file);
+          restore_table(table, &record[1..]);
+          for col in &table.columns {
+            if let Some(hash) = &col.hash { *hash.seen.borrow_mut() = self.restore_map(&table.name, file, settings); } // The ids seen for collision detection
+          }
+        },
+        Node::Domain(domain) => {
+          let mut domain = domain.borrow_mut();
+          let record = self.next_record("domain", &domain.table.name, file);
+          domain.lastid = record[1].parse().unwrap();
+          restore_table(&domain.table, &record[2..]);
+          domain.map = self.restore_map(&domain.table.name, file, settings);
+          if let Some(hash) = &domain.hash { *hash.seen.borrow_mut() = self.restore_map(&domain.table.name, file, settings); }
+        }
+      });
+    }
+    if self.read(file).is_some() { fatalerr!("Error: checkpoint file '{}' does not match the configuration", file); }
+  }
+  fn restore_map(&mut self, name: &str, file: &str, settings: &Settings) -> DiskMap { // From the copy of its index or from the values that follow
+    let invalid = || -> ! { fatalerr!("Error: checkpoint file '{}' is invalid", file) };
+    let number = |field: &str| field.parse::<u64>().unwrap_or_else(|_| invalid());
+    match self.read(file) {
+      Some(record) if record[0] == "index" && record.len() == 5 => {
+        self.indexes.push(record[1].clone());
+        DiskMap::load(&record[1], (number(&record[2]), number(&record[3]), number(&record[4])), settings.spill)
+      },
+      Some(record) if record[0] == "values" => {
+        let mut map = DiskMap::new(settings.spill);
+        while self.peek(file).is_some_and(|r| r[0] == "value") {
+          let record = self.read(file).unwrap();
+          if record.len() != 3 { invalid(); }
+          map.insert(record[1].clone(), number(&record[2]));
+        }
+        map
+      },
+      _ => fatalerr!("Error: checkpoint file '{}' does not match the configuration at {}", file, name)
+    }
+  }
+  fn next_record(&mut self, kind: &str, name: &str, file: &str) -> Vec<String> {
+    match self.read(file) {
+      Some(record) if record[0] == kind && record.len() == if kind == "domain" { 7 } else { 6 } && record[if kind == "domain" { 2 } else { 1 }] == name => record,
+      _ => fatalerr!("Error: checkpoint file '{}' does not match the configuration at {} {}", file, kind, name)
+    }
+  }
+  fn read(&mut self, file: &str) -> Option<Vec<String>> {
+    self.peek(file);
+    self.next.take()
+  }
+  fn peek(&mut self, file: &str) -> Option<&Vec<String>> {
+    if self.next.is_none() {
+      self.next = self.lines.next().map(|line| {
+        let line = line.unwrap_or_else(|err| fatalerr!("Error: failed to read checkpoint file '{}': {}", file, err));
+        line.split('\t').map(unescape_text).collect()
+      });
+    }
+    self.next.as_ref()
+  }
+}
+
+enum Node<'a, 'b> {
+  Table(&'b Table<'a>),
+  Domain(&'b RefCell<Domain<'a>>)
+}
+fn visit<'a, 'b>(table: &'b Table<'a>, f: &mut dyn FnMut(Node<'a, 'b>)) { // The table, its subtables and their domains, in configuration order
+  f(Node::Table(table));
+  if let Some(domain) = table.domain.as_ref() { f(Node::Domain(domain)); }
+  for col in &table.columns {
+    if let Some(domain) = &col.domain { f(Node::Domain(domain)); }
+    if let Some(sub) = &col.subtable { visit(sub, f); }
+  }
+}
+pub fn for_each_table(maintables: &[Table], f: &mut dyn FnMut(&Table)) {
+  for table in maintables {
+    visit(table, &mut |node| match node {
+      Node::Table(table) => f(table),
+      Node::Domain(domain) => f(&domain.borrow().table)
+    });
+  }
+}
+
+fn table_record(table: &Table) -> String { // Name, rows, serial, columns found and output length
+  let serial = table.columns.iter().find_map(|c| c.serial.as_ref()).map_or(String::from("-"), |serial| serial.get().to_string());
+  let used = table.columns.iter().map(|c| if *c.used.borrow() { '1' } else { '0' }).collect::<String>();
+  let length = match &table.file {
+    Some(file) if table.cardinality != Cardinality::None => fs::metadata(file).unwrap_or_else(|err| fatalerr!("Error: failed to read length of output file '{}': {}", file, err)).len().to_string(),
+    _ => String::from("-")
+  };
+  format!("{}\t{}\t{}\t{}\t{}", escape(&table.name), table.rows.get(), serial, used, length)
+}
+fn map_record(out: &mut String, map: &DiskMap, prefix: &str, indexes: &mut Vec<String>) { // Values on disk are referred to in a copy of their index
+  let path = format!("{}.{}", prefix, indexes.len());
+  match map.save(&path) {
+    Some((capacity, count, keylen)) => {
+      writeln!(out, "index\t{}\t{}\t{}\t{}", escape(&path), capacity, count, keylen).unwrap();
+      indexes.push(path);
+    },
+    None => {
+      writeln!(out, "values").unwrap();
+      map.for_each(|key, id| writeln!(out, "value\t{}\t{}", escape(key), id).unwrap());
+    }
+  }
+}
+fn remove_indexes(indexes: &[String]) {
+  for path in indexes {
+    for kind in ["slots", "keys"] { let _ = fs::remove_file(format!("{}.{}", path, kind)); }
+  }
+}
+fn restore_table(table: &Table, record: &[String]) {
+  table.rows.set(record[1].parse().unwrap_or_default());
+  if let (Some(serial), Ok(lastid)) = (table.columns.iter().find_map(|c| c.serial.as_ref()), record[2].parse()) { serial.set(lastid); }
+  for (col, used) in table.columns.iter().zip(record[3].chars()) { *col.used.borrow_mut() = used == '1'; }
+  if let (Some(file), Ok(length)) = (&table.file, record[4].parse::<u64>()) {
+    OpenOptions::new().write(true).open(file).and_then(|f| f.set_len(length))
+      .unwrap_or_else(|err| fatalerr!("Error: failed to truncate output file '{}' to the checkpoint: {}", file, err));
+  }
+}
+
+fn escape(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
@@ -0,0 +1,266 @@
+// Value to id map for the domains of 'norm' columns that moves to a hash index on disk once it grows too large
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use xxhash_rust::xxh64::xxh64;
+
+const SLOT: u64 = 24; // Hash, offset of the key and id (all u64); a zero hash marks an empty slot
+static FILES: AtomicUsize = AtomicUsize::new(0);
+
+pub struct DiskMap {
+  memory: HashMap<String, (u64, u64)>, // Id and the last use of each value; once on disk, the most recently used values only
+  recent: BTreeMap<u64, String>, // The values in memory by their last use, once on disk
+  uses: u64,
+  limit: Option<usize>, // Number of values kept in memory before they move to disk
+  disk: Option<Index> // Holds all values once it exists
+}
+impl DiskMap {
+  pub fn new(limit: Option<usize>) -> DiskMap {
+    DiskMap { memory: HashMap::new(), recent: BTreeMap::new(), uses: 0, limit, disk: None }
+  }
+  pub fn get(&mut self, key: &str) -> Option<u64> {
+    if let Some((id, last)) = self.memory.get_mut(key) {
+      if self.disk.is_some() { // Keep frequent values in memory
+        self.uses += 1;
+        let key = self.recent.remove(last).unwrap();
+        self.recent.insert(self.uses, key);
+        *last = self.uses;
+      }
+      return Some(*id);
+    }
+    let id = self.disk.as_ref()?.get(key)?;
+    self.cache(key.to_owned(), id);
+    Some(id)
+  }
+  pub fn insert(&mut self, key: String, id: u64) {
+    if let Some(disk) = &mut self.disk {
+      disk.insert(&key, id);
+      self.cache(key, id);
+      return;
+    }
+    self.memory.insert(key, (id, 0));
+    if self.limit.is_some_and(|limit| self.memory.len() > limit) { // Move all values to disk, keeping them in memory as the first cached ones
+      let mut disk = Index::new();
+      for (key, (id, last)) in self.memory.iter_mut() {
+        disk.insert(key, *id);
+        self.uses += 1;
+        *last = self.uses;
+        self.recent.insert(self.uses, key.clone());
+      }
+      self.disk = Some(disk);
+      self.evict();
+    }
+  }
+  fn cache(&mut self, key: String, id: u64) {
+    self.uses += 1;
+    self.recent.insert(self.uses, key.clone());
+    self.memory.insert(key, (id, self.uses));
+    self.evict();
+  }
+  fn evict(&mut self) { // Drops the least recently used values from memory
+    while self.limit.is_some_and(|limit| self.memory.len() > limit) {
+      let (_, key) = self.recent.pop_first().unwrap();
+      self.memory.remove(&key);
+    }
+  }
+  pub fn save(&self, path: &str) -> Option<(u64, u64, u64)> { // Copies the index to path.slots and path.keys once the values are on disk; returns its capacity, count and key length
+    let disk = self.disk.as_ref()?;
+    for (mut from, kind, len) in [(&disk.slots, "slots", disk.capacity*SLOT), (&disk.keys, "keys", disk.keylen)] { // From the open files, as they may be unlinked
+      from.seek(SeekFrom::Start(0)).and_then(|_| File::create(format!("{}.{}", path, kind))).and_then(|mut to| io::copy(&mut from.take(len), &mut to))
+        .unwrap_or_else(|err| fatalerr!("Error: failed to copy domain index to '{}.{}': {}", path, kind, err));
+    }
+    Some((disk.capacity, disk.count, disk.keylen))
+  }
+  pub fn load(path: &str, (capacity, count, keylen): (u64, u64, u64), limit: Option<usize>) -> DiskMap { // Continues from an index saved by save(), in copies of its files
+    let copy = |kind: &str, len: u64| {
+      let from = format!("{}.{}", path, kind);
+      let (mut file, temp) = temp_file(kind);
+      File::open(&from).and_then(|mut f| io::copy(&mut f, &mut file)).and_then(|n| if n == len { Ok(()) } else { Err(io::Error::new(io::ErrorKind::InvalidData, "length does not match the checkpoint")) }).unwrap_or_else(|err| fatalerr!("Error: failed to read domain index '{}': {}", from, err));
+      (file, temp)
+    };
+    let (slots, slotpath) = copy("slots", capacity*SLOT);
+    let (keys, keypath) = copy("keys", keylen);
+    let disk = Index { slots, keys, paths: [slotpath, keypath], capacity, count, keylen };
+    DiskMap { memory: HashMap::new(), recent: BTreeMap::new(), uses: 0, limit, disk: Some(disk) }
+  }
+  pub fn for_each(&self, mut f: impl FnMut(&str, u64)) {
+    match &self.disk {
+      Some(disk) => disk.for_each(&mut f),
+      None => for (key, (id, _)) in &self.memory { f(key, *id); }
+    }
+  }
+}
+
+struct Index { // Open addressing hash table in one file, with the keys appended to another
+  slots: File,
+  keys: File,
+  paths: [Option<PathBuf>; 2], // Only where the files could not be unlinked right away
+  capacity: u64, // Number of slots; a power of two
+  count: u64,
+  keylen: u64
+}
+impl Index {
+  fn new() -> Index {
+    let (slots, slotpath) = temp_file("slots");
+    let (keys, keypath) = temp_file("keys");
+    let mut index = Index { slots, keys, paths: [slotpath, keypath], capacity: 0, count: 0, keylen: 0 };
+    index.resize(1 << 16);
+    index
+  }
+  fn get(&self, key: &str) -> Option<u64> {
+    let hash = hash(key);
+    let mut i = hash & (self.capacity-1);
+    loop {
+      let (slothash, offset, id) = self.read_slot(i);
+      if slothash == 0 { return None; }
+      if slothash == hash && self.read_key(offset) == key.as_bytes() { return Some(id); }
+      i = (i+1) & (self.capacity-1);
+    }
+  }
+  fn insert(&mut self, key: &str, id: u64) {
+    if (self.count+1)*2 > self.capacity { self.resize(self.capacity*2); }
+    let offset = self.keylen;
+    let mut keys = &self.keys;
+    keys.seek(SeekFrom::Start(offset)).and_then(|_| keys.write_all(&(key.len() as u32).to_le_bytes())).and_then(|_| keys.write_all(key.as_bytes()))
+      .unwrap_or_else(|err| fatalerr!("Error: failed to write domain index to disk: {}", err));
+    self.keylen += 4 + key.len() as u64;
+    self.place(hash(key), offset, id);
+    self.count += 1;
+  }
+  fn place(&self, hash: u64, offset: u64, id: u64) {
+    let mut i = hash & (self.capacity-1);
+    while self.read_slot(i).0 != 0 { i = (i+1) & (self.capacity-1); }
+    let mut slot = [0u8; SLOT as usize];
+    slot[0..8].copy_from_slice(&hash.to_le_bytes());
+    slot[8..16].copy_from_slice(&offset.to_le_bytes());
+    slot[16..24].copy_from_slice(&id.to_le_bytes());
+    let mut slots = &self.slots;
+    slots.seek(SeekFrom::Start(i*SLOT)).and_then(|_| slots.write_all(&slot)).unwrap_or_else(|err| fatalerr!("Error: failed to write domain index to disk: {}", err));
+  }
+  fn resize(&mut self, capacity: u64) { // Moves the slots to a new file with more of them
+    let (slots, path) = temp_file("slots");
+    slots.set_len(capacity*SLOT).unwrap_or_else(|err| fatalerr!("Error: failed to extend domain index on disk: {}", err));
+    let mut old = mem::replace(&mut self.slots, slots);
+    if let Some(old) = mem::replace(&mut self.paths[0], path) { let _ = fs::remove_file(old); }
+    let oldcapacity = mem::replace(&mut self.capacity, capacity);
+    if oldcapacity == 0 { return; }
+    old.seek(SeekFrom::Start(0)).unwrap_or_else(|err| fatalerr!("Error: failed to read domain index from disk: {}", err));
+    let mut buf = vec![0u8; (SLOT*4096) as usize];
+    let mut left = oldcapacity;
+    while left > 0 {
+      let n = left.min(4096);
+      old.read_exact(&mut buf[..(n*SLOT) as usize]).unwrap_or_else(|err| fatalerr!("Error: failed to read domain index from disk: {}", err));
+      for slot in buf[..(n*SLOT) as usize].chunks(SLOT as usize) {
+        let hash = u64::from_le_bytes(slot[0..8].try_into().unwrap());
+        if hash != 0 { self.place(hash, u64::from_le_bytes(slot[8..16].try_into().unwrap()), u64::from_le_bytes(slot[16..24].try_into().unwrap())); }
+      }
+      left -= n;
+    }
+  }
+  fn for_each(&self, f: &mut impl FnMut(&str, u64)) {
+    let mut slots = &self.slots;
+    slots.seek(SeekFrom::Start(0)).unwrap_or_else(|err| fatalerr!("Error: failed to read domain index from disk: {}", err));
+    let mut buf = vec![0u8; (SLOT*4096) as usize];
+    let mut left = self.capacity;
+    while left > 0 {
+      let n = left.min(4096);
+      slots.read_exact(&mut buf[..(n*SLOT) as usize]).unwrap_or_else(|err| fatalerr!("Error: failed to read domain index from disk: {}", err));
+      for slot in buf[..(n*SLOT) as usize].chunks(SLOT as usize) {
+        if u64::from_le_bytes(slot[0..8].try_into().unwrap()) == 0 { continue; }
+        let key = self.read_key(u64::from_le_bytes(slot[8..16].try_into().unwrap()));
+        f(&String::from_utf8_lossy(&key), u64::from_le_bytes(slot[16..24].try_into().unwrap()));
+      }
+      left -= n;
+    }
+  }
+  fn read_slot(&self, i: u64) -> (u64, u64, u64) {
+    let mut slot = [0u8; SLOT as usize];
+    let mut slots = &self.slots;
+    slots.seek(SeekFrom::Start(i*SLOT)).and_then(|_| slots.read_exact(&mut slot)).unwrap_or_else(|err| fatalerr!("Error: failed to read domain index from disk: {}", err));
+    (u64::from_le_bytes(slot[0..8].try_into().unwrap()), u64::from_le_bytes(slot[8..16].try_into().unwrap()), u64::from_le_bytes(slot[16..24].try_into().unwrap()))
+  }
+  fn read_key(&self, offset: u64) -> Vec<u8> {
+    let mut keys = &self.keys;
+    let mut len = [0u8; 4];
+    keys.seek(SeekFrom::Start(offset)).and_then(|_| keys.read_exact(&mut len)).unwrap_or_else(|err| fatalerr!("Error: failed to read domain index from disk: {}", err));
+    let mut key = vec![0u8; u32::from_le_bytes(len) as usize];
+    keys.read_exact(&mut key).unwrap_or_else(|err| fatalerr!("Error: failed to read domain index from disk: {}", err));
+    key
+  }
+}
+impl Drop for Index {
+  fn drop(&mut self) {
+    for path in self.paths.iter().flatten() { let _ = fs::remove_file(path); }
+  }
+}
+
+fn hash(key: &str) -> u64 { // Stable across builds, as indexes are kept in checkpoints
+  xxh64(key.as_bytes(), 0).max(1) // Zero marks an empty slot
+}
+fn temp_file(kind: &str) -> (File, Option<PathBuf>) { // Unlinked while open where the system allows it, so it is gone also after a fatal error
+  let path = env::temp_dir().join(format!("xml-to-postgres-{}-{}.{}", std::process::id(), FILES.fetch_add(1, Ordering::Relaxed), kind));
+  let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path).unwrap_or_else(|err| fatalerr!("Error: failed to create domain index file '{}': {}", path.display(), err));
+  if cfg!(unix) && fs::remove_file(&path).is_ok() { return (file, None); }
+  (file, Some(path))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn spill_and_resize() {
+    let mut map = DiskMap::new(Some(100));
+    for i in 0..40_000u64 { // Past the spill limit and the initial capacity of the index (1 << 16 slots at half load)
+      map.insert(format!("value {}", i), i+1);
+      assert_eq!(map.get("value 0"), Some(1)); // Used all the time, so it stays in memory
+    }
+    assert_eq!(map.disk.as_ref().unwrap().capacity, 1 << 17);
+    assert!(map.memory.len() <= 100 && map.recent.len() == map.memory.len());
+    assert!(map.memory.contains_key("value 0"));
+    for i in 0..40_000u64 { assert_eq!(map.get(&format!("value {}", i)), Some(i+1)); }
+    assert_eq!(map.get("value 40000"), None);
+    assert_eq!(map.get(""), None);
+  }
+
+  #[test]
+  fn for_each_after_spill() {
+    let mut map = DiskMap::new(Some(10));
+    for i in 0..1000u64 { map.insert(i.to_string(), i); }
+    let mut seen = HashMap::new();
+    map.for_each(|key, id| assert!(seen.insert(key.to_owned(), id).is_none()));
+    assert_eq!(seen.len(), 1000);
+    assert!(seen.iter().all(|(key, id)| key.parse::<u64>() == Ok(*id)));
+  }
+
+  #[test]
+  fn save_and_load() {
+    let mut map = DiskMap::new(Some(10));
+    assert_eq!(map.save("unused"), None);
+    for i in 0..1000u64 { map.insert(i.to_string(), i); }
+    let path = env::temp_dir().join(format!("xml-to-postgres-test-{}", std::process::id())).to_string_lossy().into_owned();
+    let saved = map.save(&path).unwrap();
+    map.insert(String::from("after"), 1000); // Not in the saved copy
+    let mut loaded = DiskMap::load(&path, saved, Some(10));
+    for kind in ["slots", "keys"] { fs::remove_file(format!("{}.{}", path, kind)).unwrap(); }
+    for i in 0..1000u64 { assert_eq!(loaded.get(&i.to_string()), Some(i)); }
+    assert_eq!(loaded.get("after"), None);
+    loaded.insert(String::from("after"), 1001);
+    assert_eq!(loaded.get("after"), Some(1001));
+    assert_eq!(map.get("after"), Some(1000));
+  }
+
+  #[test]
+  fn memory_only() {
+    let mut map = DiskMap::new(None);
+    for i in 0..1000u64 { map.insert(i.to_string(), i); }
+    assert!(map.disk.is_none() && map.recent.is_empty());
+    assert_eq!(map.get("999"), Some(999));
+    assert_eq!(map.get("1000"), None);
+  }
+}
@@ -1,18 +1,20 @@
 use std::borrow::Cow;
-use std::io::{stdin, stdout, BufRead, BufReader, IsTerminal as _, Read, Write};
+use std::fmt::Write as _;
+use std::io::{stdin, stdout, BufRead, BufReader, Cursor, ErrorKind, IsTerminal as _, Read, Seek, Write};
 use std::fs::{File, OpenOptions};
 use std::mem;
-use std::fmt::Write as _;
+use std::ptr;
 use std::path::Path;
 use std::env;
 use std::cell::{ Cell, RefCell };
-use std::time::Instant;
-use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread;
 use std::default::Default;
-use std::collections::HashMap;
-use quick_xml::Reader;
-use quick_xml::events::Event;
+use std::collections::{BTreeMap, HashMap};
+use quick_xml::{Decoder, Reader};
+use quick_xml::events::{BytesStart, Event};
 use yaml_rust2::YamlLoader;
 use yaml_rust2::yaml::Yaml;
 use regex::Regex;
@@ -20,6 +22,19 @@ use lazy_static::lazy_static;
 use cow_utils::CowUtils;
 use git_version::git_version;
 use glob_match::glob_match;
+use flate2::bufread::MultiGzDecoder;
+use bzip2::bufread::MultiBzDecoder;
+use liblzma::bufread::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+use zip::ZipArchive;
+use encoding_rs::{Encoding, UTF_8, UTF_16BE, UTF_16LE};
+use encoding_rs_io::DecodeReaderBytesBuilder;
+use postgres::{Client, NoTls};
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use checkpoint::{Checkpoint, Resume};
+use diskmap::DiskMap;
+use uuid::Uuid;
+use xxhash_rust::xxh64::xxh64;
 
 macro_rules! fatalerr {
   () => ({
@@ -32,14 +47,44 @@ macro_rules! fatalerr {
   });
 }
 
+macro_rules! warning { // Warnings are counted by category for the --stats report, also when hushed
+  ($hush:expr, $category:expr, $($arg:tt)*) => ({
+    *WARNINGS.lock().unwrap().entry($category).or_insert(0) += 1;
+    if !$hush { eprintln!($($arg)*); }
+  });
+}
+
+lazy_static! {
+  static ref WARNINGS: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
+}
+
+mod check;
+mod checkpoint;
+mod diskmap;
+mod scan;
+mod xsd;
+
 struct Settings {
   filemode: String,
-  skip: String,
+  zipglob: String,
+  encoding: Option<&'static Encoding>,
+  namespaces: HashMap<String, String>,
+  ns_ignore: bool,
+  format: Format,
+  delimiter: u8,
+  header: bool,
+  mismatch: Option<Mismatch>, // Default policy for values that don't match their column type
+  spill: Option<usize>, // Number of values a domain keeps in memory before moving them to disk
+  explain: bool,
+  resume: bool, // Output files are continued from a checkpoint
+  connection: Option<String>,
+  transactions: Arc<Mutex<Vec<Client>>>, // Database connections with their COPY completed, waiting for the final commit
   emit_copyfrom: bool,
   emit_createtable: bool,
   emit_starttransaction: bool,
   emit_truncate: bool,
   emit_droptable: bool,
+  emit_identity: bool,
   hush_version: bool,
   hush_info: bool,
   hush_notice: bool,
@@ -47,6 +92,42 @@ struct Settings {
   show_progress: bool
 }
 
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Format {
+  Text,
+  Csv,
+  Binary
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+enum BinaryType {
+  #[default]
+  Text,
+  Jsonb,
+  Bool,
+  Int2,
+  Int4,
+  Int8,
+  Float4,
+  Float8,
+  Numeric,
+  Date,
+  Timestamp,
+  TimestampTz,
+  Bytea,
+  Uuid,
+  Geometry
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Mismatch {
+  Error,
+  Null,
+  Skip
+}
+
+const KEYSEP: &str = "\x1f"; // Separates the values of a key of several columns
+
 #[derive(Copy, Clone, PartialEq, Debug)]
 enum Cardinality {
   Default,
@@ -59,50 +140,173 @@ enum Cardinality {
 struct Table<'a> {
   name: String,
   path: String,
-  buf: RefCell<String>,
-  writer_channel: mpsc::SyncSender<String>,
+  file: Option<String>,
+  skip: String, // Absolute path of the elements to skip in the rows of a main table
+  preamble: RefCell<String>, // The statements emit_preamble() would write, for --explain
+  buf: RefCell<Vec<u8>>,
+  writer_channel: mpsc::SyncSender<Vec<u8>>,
   writer_thread: Option<thread::JoinHandle<()>>,
+  sent: Cell<u64>, // Buffers sent to the writer thread
+  written: Arc<AtomicU64>, // Buffers written out by the writer thread
   columns: Vec<Column<'a>>,
-  lastid: RefCell<String>,
+  key: Vec<usize>, // Columns whose values make up the key given to subtables
+  lastid: RefCell<String>, // The key of the current row; with several columns, their values separated by KEYSEP
+  badkey: RefCell<Option<(Mismatch, String)>>, // A key value of the current row that did not match its type, with the policy applied and the reason
+  rows: Cell<u64>,
+  marks: RefCell<Vec<(usize, u64)>>, // Buffer length and row count at the start of each enclosing row, as subtable rows are held until it is accepted
   domain: Box<Option<RefCell<Domain<'a>>>>,
   cardinality: Cardinality,
   emit_copyfrom: bool,
-  emit_starttransaction: bool
+  emit_starttransaction: bool,
+  emit_identity: bool,
+  database: bool,
+  binary: bool
 }
 impl<'a> Table<'a> {
   fn new(name: &str, path: &str, file: Option<&str>, settings: &Settings, cardinality: Cardinality) -> Table<'a> {
     //println!("Table {} path {} file {:?} cardinality {:?}", name, path, file, cardinality);
-    let out: RefCell<Box<dyn Write + Send>> = match file {
-      None => RefCell::new(Box::new(stdout())),
-      Some(ref file) => RefCell::new(Box::new(
-        match settings.filemode.as_ref() {
-          "truncate" => File::create(Path::new(file)).unwrap_or_else(|err| fatalerr!("Error: failed to create output file '{}': {}", file, err)),
-          "append" => OpenOptions::new().append(true).create(true).open(Path::new(file)).unwrap_or_else(|err| fatalerr!("Error: failed to open output file '{}': {}", file, err)),
-          mode => fatalerr!("Error: invalid 'mode' setting in configuration file: {}", mode)
-        }
-      ))
-    };
+    let database = cardinality != Cardinality::None && settings.connection.is_some() && !settings.explain;
     let (writer_channel, rx) = mpsc::sync_channel(100);
-    let writer_thread = thread::Builder::new().name(format!("write {}", name)).spawn(move || write_output(out, rx)).unwrap_or_else(|err| fatalerr!("Error: failed to create writer thread: {}", err));
+    let written = Arc::new(AtomicU64::new(0));
+    let writer_thread = match database {
+      true => { // Rows go into a COPY on a database connection of their own
+        let client = Client::connect(settings.connection.as_ref().unwrap(), NoTls).unwrap_or_else(|err| fatalerr!("Error: failed to connect to database for table {}: {}", name, db_error(&err)));
+        let transactions = settings.transactions.clone();
+        let tabname = name.to_owned();
+        thread::Builder::new().name(format!("copy {}", name)).spawn(move || write_database(client, rx, &tabname, transactions))
+      },
+      false => {
+        let out: RefCell<Box<dyn Write + Send>> = match file {
+          _ if settings.explain => RefCell::new(Box::new(std::io::sink())),
+          None => RefCell::new(Box::new(stdout())),
+          Some(ref file) => RefCell::new(Box::new(
+            match settings.filemode.as_ref() {
+              _ if settings.resume => OpenOptions::new().append(true).open(Path::new(file)).unwrap_or_else(|err| fatalerr!("Error: failed to open output file '{}' to resume: {}", file, err)),
+              "truncate" => File::create(Path::new(file)).unwrap_or_else(|err| fatalerr!("Error: failed to create output file '{}': {}", file, err)),
+              "append" => OpenOptions::new().append(true).create(true).open(Path::new(file)).unwrap_or_else(|err| fatalerr!("Error: failed to open output file '{}': {}", file, err)),
+              mode => fatalerr!("Error: invalid 'mode' setting in configuration file: {}", mode)
+            }
+          ))
+        };
+        let written = written.clone();
+        thread::Builder::new().name(format!("write {}", name)).spawn(move || write_output(out, rx, written))
+      }
+    }.unwrap_or_else(|err| fatalerr!("Error: failed to create writer thread: {}", err));
     let mut ownpath = String::from(path);
     if !ownpath.is_empty() && !ownpath.starts_with('/') { ownpath.insert(0, '/'); }
     if ownpath.ends_with('/') { ownpath.pop(); }
     Table {
       name: name.to_owned(),
       path: ownpath,
-      buf: RefCell::new(String::new()),
+      file: file.map(String::from),
+      skip: String::new(),
+      preamble: RefCell::new(String::new()),
+      buf: RefCell::new(Vec::new()),
       writer_channel,
       writer_thread: Some(writer_thread),
+      sent: Cell::new(0),
+      written,
       columns: Vec::new(),
+      key: vec![0],
       lastid: RefCell::new(String::new()),
+      badkey: RefCell::new(None),
+      rows: Cell::new(0),
+      marks: RefCell::new(Vec::new()),
       domain: Box::new(None),
       cardinality,
-      emit_copyfrom: if cardinality != Cardinality::None { settings.emit_copyfrom } else { false },
-      emit_starttransaction: if cardinality != Cardinality::None { settings.emit_starttransaction } else { false }
+      emit_copyfrom: if cardinality != Cardinality::None && !database { settings.emit_copyfrom } else { false },
+      emit_starttransaction: if cardinality != Cardinality::None && !database { settings.emit_starttransaction } else { false },
+      emit_identity: settings.emit_identity && cardinality != Cardinality::None && cardinality != Cardinality::ManyToMany,
+      database,
+      binary: cardinality != Cardinality::None && settings.format == Format::Binary
     }
   }
   fn flush(&self) {
-    if self.buf.borrow().len() > 0 { self.writer_channel.send(std::mem::take(&mut self.buf.borrow_mut())).unwrap(); }
+    if self.buf.borrow().is_empty() { return; }
+    self.writer_channel.send(std::mem::take(&mut self.buf.borrow_mut())).unwrap();
+    self.sent.set(self.sent.get()+1);
+  }
+  fn sync(&self) { // Flushes the buffer and waits until the writer thread has written it out
+    self.flush();
+    while self.written.load(Ordering::Acquire) < self.sent.get() { thread::sleep(Duration::from_millis(1)); }
+  }
+  fn end_row(&self, row: Row, settings: &Settings) {
+    row.end(&mut self.buf.borrow_mut(), settings);
+    self.rows.set(self.rows.get()+1);
+    if self.marks.borrow().is_empty() { self.flush(); } // Subtable rows wait for the rows they belong to
+  }
+  fn for_each_held(&self, f: &mut dyn FnMut(&Table)) { // The subtables whose rows belong to a row of this table; many-to-one rows are referred to by id, so they are kept
+    for sub in self.columns.iter().filter_map(|c| c.subtable.as_ref()).filter(|sub| sub.cardinality != Cardinality::ManyToOne) {
+      f(sub);
+      sub.for_each_held(f);
+    }
+  }
+  fn hold_rows(&self) { // At the start of a row
+    self.for_each_held(&mut |sub| sub.marks.borrow_mut().push((sub.buf.borrow().len(), sub.rows.get())));
+  }
+  fn release_rows(&self, accepted: bool) { // At the end of a row; rows of a skipped or filtered one are dropped
+    self.for_each_held(&mut |sub| {
+      let (len, rows) = sub.marks.borrow_mut().pop().unwrap();
+      if !accepted {
+        sub.buf.borrow_mut().truncate(len);
+        sub.rows.set(rows);
+      }
+      else if sub.marks.borrow().is_empty() { sub.flush(); }
+    });
+  }
+  fn update_key(&self) { // Empty until all key columns have a value, or when the row is skipped over an invalid one
+    let mut lastid = self.lastid.borrow_mut();
+    lastid.clear();
+    if self.key.iter().any(|i| self.columns[*i].value.borrow().is_empty()) { return; }
+    if self.badkey.borrow().as_ref().is_some_and(|(mismatch, _)| *mismatch == Mismatch::Skip) { return; }
+    lastid.push_str(&self.key.iter().map(|i| self.columns[*i].value.borrow().to_string()).collect::<Vec<String>>().join(KEYSEP));
+  }
+  fn check_key(&self, i: usize, nullcount: &mut u64, filename: &str, position: u64) { // Key values are checked as soon as they are complete, as subtables take them over before the end of the row
+    match self.columns[i].check() {
+      Ok(()) => (),
+      Err((Mismatch::Error, reason)) => fatalerr!("Error: table {} {} (in {} at position {})", self.name, reason, filename, position),
+      Err((mismatch, reason)) => { // A skipped row keeps the value for the rejects file
+        if mismatch == Mismatch::Null { *nullcount += 1; }
+        self.badkey.replace(Some((mismatch, reason)));
+      }
+    }
+    self.update_key();
+  }
+  fn write_key(&self, row: &mut Row, buf: &mut Vec<u8>, settings: &Settings) { // Writes the key of the current row as (foreign key) fields
+    let lastid = self.lastid.borrow();
+    let mut values = lastid.split(KEYSEP);
+    for i in &self.key { row.field(buf, values.next().unwrap_or(""), self.columns.get(*i), settings); }
+  }
+  fn key_columns(&self) -> Vec<String> { // Names and types of the columns referring to the key of this table
+    match self.key[..] {
+      [i] => vec![format!("{} {}", self.name, self.columns.get(i).map_or("integer", |c| &c.datatype))],
+      _ => self.key.iter().map(|i| format!("{}_{} {}", self.name, self.columns[*i].name, self.columns[*i].datatype)).collect()
+    }
+  }
+  fn update_hashes(&self, changed: Option<usize>) { // Derives hashed ids when their columns are complete, or anyway at the end of the row (changed is None)
+    for (i, col) in self.columns.iter().enumerate() {
+      let Some(hash) = &col.hash else { continue };
+      match changed {
+        Some(changed) if !hash.columns.contains(&changed) || hash.columns.iter().any(|c| self.columns[*c].value.borrow().is_empty()) => continue,
+        None if !col.value.borrow().is_empty() => continue,
+        _ => ()
+      }
+      let source = hash.columns.iter().map(|c| self.columns[*c].value.borrow().to_string()).collect::<Vec<String>>().join(KEYSEP);
+      *col.value.borrow_mut() = hash.value(&source);
+      if self.key.contains(&i) { self.update_key(); }
+    }
+  }
+  fn last_serial(&self) -> Option<(&str, u64)> { // The 'seri' column with the last id given out, or the highest one of its domain
+    let col = self.columns.iter().find(|c| c.serial.is_some() && c.hash.is_none())?;
+    Some((&col.name, match self.domain.as_ref() {
+      Some(domain) => domain.borrow().lastid,
+      None => col.serial.as_ref().unwrap().get()
+    }))
+  }
+  fn set_serial(&self, id: u64) { // Normalized rows take their id from the domain instead of the serial
+    if self.columns[0].serial.is_none() || self.columns[0].hash.is_some() { return; }
+    *self.columns[0].value.borrow_mut() = id.to_string();
+    self.update_key();
   }
   fn clear_columns(&self) {
     for col in &self.columns {
@@ -112,28 +316,163 @@ impl<'a> Table<'a> {
 }
 impl<'a> Drop for Table<'a> {
   fn drop(&mut self) {
-    if self.emit_copyfrom { write!(self.buf.borrow_mut(), "\\.\n").unwrap(); }
-    if self.emit_starttransaction { write!(self.buf.borrow_mut(), "COMMIT;\n").unwrap(); }
+    if self.binary { self.buf.borrow_mut().extend_from_slice(&(-1i16).to_be_bytes()); } // Binary COPY trailer
+    else if self.emit_copyfrom { writeln!(self.buf.borrow_mut(), "\\.").unwrap(); }
+    let setval = match self.last_serial() { // Moves the sequence of an identity or serial column past the loaded ids
+      Some((column, lastid)) if self.emit_identity && (self.emit_copyfrom || self.database) && lastid > 0 => format!("SELECT setval(pg_get_serial_sequence('{}', '{}'), {});\n", self.name, column, lastid),
+      _ => String::new()
+    };
+    if !self.database { self.buf.borrow_mut().extend_from_slice(setval.as_bytes()); }
+    if self.emit_starttransaction { writeln!(self.buf.borrow_mut(), "COMMIT;").unwrap(); }
     self.flush();
-    self.writer_channel.send(String::new()).unwrap(); // Terminates the writer thread
+    self.writer_channel.send(Vec::new()).unwrap(); // Terminates the writer thread
+    if self.database { self.writer_channel.send(setval.into_bytes()).unwrap(); } // Run after the COPY
     let thread = std::mem::take(&mut self.writer_thread);
     thread.unwrap().join().unwrap_or_else(|_| eprintln!("Table writer thread for [{}] crashed", self.name));
   }
 }
 
 struct Domain<'a> {
-  lastid: u32,
-  map: HashMap<String, u32>,
+  lastid: u64,
+  map: DiskMap,
+  hash: Option<IdHash>, // Ids are derived from the values instead of numbered
   table: Table<'a>
 }
 impl<'a> Domain<'a> {
   fn new(tabname: &str, filename: Option<&str>, settings: &Settings) -> Domain<'a> {
     Domain {
       lastid: 0,
-      map: HashMap::new(),
+      map: DiskMap::new(settings.spill),
+      hash: None,
       table: Table::new(tabname, "_domain_", filename, settings, match filename { Some(_) => Cardinality::ManyToOne, None => Cardinality::None })
     }
   }
+  fn load(&mut self, file: &str, rows: impl Iterator<Item = Vec<String>>, keyfields: Option<&[usize]>) { // Continues from the values and ids of a previous run; without keyfields, rows start with their id
+    for row in rows {
+      let (key, id) = match keyfields {
+        None => (row[1..].concat(), row[0].parse::<u64>().unwrap_or_else(|_| fatalerr!("Error: dictionary file '{}' has invalid id '{}'", file, row[0]))),
+        Some(fields) => (fields.iter().map(|i| row.get(*i).map_or("", |f| f.as_str())).collect::<Vec<&str>>().join(KEYSEP), self.lastid+1)
+      };
+      if let Some(hash) = &self.hash { hash.id(&key); } // Registers the value for collision detection
+      self.lastid = self.lastid.max(id);
+      self.map.insert(key, id);
+    }
+  }
+  fn next_id(&mut self, key: &str) -> u64 {
+    if let Some(hash) = &self.hash { return hash.id(key); }
+    self.lastid += 1;
+    self.lastid
+  }
+}
+struct IdHash { // Derives stable ids from values: 63 bits of xxHash64 or a UUIDv5
+  name: String,
+  uuid: bool,
+  columns: Vec<usize>, // The columns whose values are hashed, for 'seri' columns
+  escaped: bool, // Values are hashed as found in the input, whatever the output format
+  seen: RefCell<DiskMap> // Ids with a second hash of their value, to detect collisions; moves to disk like the domains
+}
+impl IdHash {
+  fn new(name: String, uuid: bool, columns: Vec<usize>, settings: &Settings) -> IdHash {
+    IdHash { name, uuid, columns, escaped: settings.format == Format::Text, seen: RefCell::new(DiskMap::new(settings.spill)) }
+  }
+  fn id(&self, value: &str) -> u64 {
+    let value = self.unescape(value);
+    let id = xxh64(value.as_bytes(), 0) & i64::MAX as u64;
+    self.register(id, &value);
+    id
+  }
+  fn value(&self, value: &str) -> String {
+    if !self.uuid { return self.id(value).to_string(); }
+    let value = self.unescape(value);
+    let uuid = Uuid::new_v5(&Uuid::NAMESPACE_OID, value.as_bytes());
+    self.register(uuid.as_u64_pair().0, &value);
+    uuid.to_string()
+  }
+  fn unescape<'v>(&self, value: &'v str) -> Cow<'v, str> {
+    match self.escaped && value.contains('\\') {
+      true => Cow::Owned(unescape_text(value)),
+      false => Cow::Borrowed(value)
+    }
+  }
+  fn register(&self, id: u64, value: &str) {
+    let check = xxh64(value.as_bytes(), 1);
+    let mut seen = self.seen.borrow_mut();
+    let key = id.to_string();
+    match seen.get(&key) {
+      Some(prev) if prev != check => fatalerr!("Error: hash collision in {}: value '{}' has the same id as an earlier one", self.name, value),
+      Some(_) => (),
+      None => seen.insert(key, check)
+    }
+  }
+}
+
+fn open_dictionary(file: &str) -> (std::io::Take<BufReader<File>>, bool) { // Opened before the outputs, as it may be one of them; limited to its current length and whether it holds COPY statements
+  let mut reader = BufReader::new(File::open(file).unwrap_or_else(|err| fatalerr!("Error: failed to open dictionary file '{}': {}", file, err)));
+  let mut line = String::new();
+  let mut len = 0;
+  let mut copy = false;
+  loop {
+    line.clear();
+    match reader.read_line(&mut line) {
+      Ok(0) => break,
+      Ok(n) => len += n as u64,
+      Err(err) => fatalerr!("Error: failed to read dictionary file '{}': {}", file, err)
+    }
+    if line.starts_with("COPY ") { copy = true; }
+  }
+  reader.rewind().unwrap_or_else(|err| fatalerr!("Error: failed to read dictionary file '{}': {}", file, err));
+  (reader.take(len), copy)
+}
+fn read_dictionary<'r>(file: &'r str, reader: std::io::Take<BufReader<File>>, copy: bool, settings: &Settings) -> impl Iterator<Item = Vec<String>> + 'r { // Reads the rows of a domain table in the output format line by line, skipping any emitted statements
+  let format = settings.format;
+  let delimiter = settings.delimiter as char;
+  let mut header = (format == Format::Csv && settings.header).then(Vec::new); // Taken from the first row and skipped wherever it is repeated
+  let mut data = !copy;
+  let mut lines = reader.lines();
+  let mut next = move || lines.next().map(|line| line.unwrap_or_else(|err| fatalerr!("Error: failed to read dictionary file '{}': {}", file, err)));
+  std::iter::from_fn(move || loop {
+    let line = next()?;
+    if copy && line.starts_with("COPY ") { data = true; }
+    else if line == "\\." { data = false; }
+    else if data && !line.is_empty() {
+      let row = match format {
+        Format::Csv => {
+          let mut record = line;
+          loop { // A quoted value can span lines
+            if let Some(row) = parse_csv(&record, delimiter) { break row; }
+            record.push('\n');
+            record.push_str(&next().unwrap_or_else(|| fatalerr!("Error: dictionary file '{}' ends within a quoted value", file)));
+          }
+        },
+        _ => line.split('\t').map(|field| match field {
+          "\\N" => String::new(),
+          _ => field.to_owned() // Values are kept escaped in this format
+        }).collect()
+      };
+      match &mut header {
+        Some(header) if header.is_empty() => *header = row,
+        Some(header) if *header == row => (),
+        _ => return Some(row)
+      }
+    }
+  })
+}
+fn parse_csv(record: &str, delimiter: char) -> Option<Vec<String>> { // None when a quoted value continues on the next line; NULL and empty values both become empty strings
+  let mut fields = Vec::new();
+  let mut field = String::new();
+  let mut quoted = false;
+  let mut chars = record.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      '"' if quoted && chars.peek() == Some(&'"') => { field.push('"'); chars.next(); },
+      '"' => quoted = !quoted,
+      c if c == delimiter && !quoted => fields.push(std::mem::take(&mut field)),
+      c => field.push(c)
+    }
+  }
+  if quoted { return None; }
+  fields.push(field);
+  Some(fields)
 }
 
 #[derive(Default)]
@@ -143,11 +482,16 @@ struct Column<'a> {
   serial: Option<Cell<u64>>,
   fkey: Option<(String, String)>,
   datatype: String,
+  bintype: BinaryType,
+  check: Option<(BinaryType, Mismatch)>,
+  formats: Vec<&'a str>,
   value: RefCell<String>,
   attr: Option<&'a str>,
   hide: bool,
   include: Option<Regex>,
   exclude: Option<Regex>,
+  strict: bool,
+  filtered: Cell<u64>,
   find: Option<Regex>,
   replace: Option<&'a str>,
   trim: bool,
@@ -155,10 +499,27 @@ struct Column<'a> {
   aggr: Option<&'a str>,
   subtable: Option<Table<'a>>,
   domain: Option<RefCell<Domain<'a>>>,
+  hash: Option<IdHash>, // For 'seri' columns with ids derived from other columns
   bbox: Option<BBox>,
   multitype: bool,
   used: RefCell<bool>
 }
+impl<'a> Column<'a> {
+  fn check(&self) -> Result<(), (Mismatch, String)> { // Normalizes the value to the column type; otherwise returns the 'fail' policy and the reason, with the value cleared for 'null'
+    let Some((bintype, mismatch)) = self.check else { return Ok(()) };
+    if self.value.borrow().is_empty() { return Ok(()); }
+    let checked = check_value(&self.value.borrow(), bintype, &self.formats);
+    match checked {
+      Some(value) => *self.value.borrow_mut() = value,
+      None => {
+        let reason = format!("column {} has value '{}' that does not match its type", self.name, self.value.borrow());
+        if mismatch == Mismatch::Null { self.value.borrow_mut().clear(); }
+        return Err((mismatch, reason));
+      }
+    }
+    Ok(())
+  }
+}
 
 #[derive(Debug)]
 struct Geometry {
@@ -190,7 +551,106 @@ impl BBox {
   }
 }
 
-#[derive(PartialEq, Debug)]
+struct Decompressor {
+  decoder: Box<dyn Read>,
+  format: &'static str,
+  input: String
+}
+impl Read for Decompressor {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    // Decompression errors are reported here; otherwise they surface as a generic XML parse failure
+    self.decoder.read(buf).map_err(|err| match err.kind() {
+      ErrorKind::UnexpectedEof => fatalerr!("Error: {} compressed input '{}' is truncated (unexpected end of compressed stream)", self.format, self.input),
+      _ => fatalerr!("Error: failed to decompress {} input '{}': {}", self.format, self.input, err)
+    })
+  }
+}
+
+struct Counter<R> { // Counts the bytes read from an input file, for the progress report
+  inner: R,
+  count: Arc<AtomicU64>
+}
+impl<R: Read> Read for Counter<R> {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    let len = self.inner.read(buf)?;
+    self.count.fetch_add(len as u64, Ordering::Relaxed);
+    Ok(len)
+  }
+}
+impl<R: Seek> Seek for Counter<R> {
+  fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+    self.inner.seek(pos)
+  }
+}
+
+struct ChannelReader {
+  rx: mpsc::Receiver<Vec<u8>>,
+  chunk: Vec<u8>,
+  pos: usize
+}
+impl Read for ChannelReader {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    let len = self.fill_buf()?.read(buf)?;
+    self.consume(len);
+    Ok(len)
+  }
+}
+impl BufRead for ChannelReader {
+  fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+    if self.pos == self.chunk.len() {
+      if let Ok(chunk) = self.rx.recv() { // A closed channel signals the end of the data
+        self.chunk = chunk;
+        self.pos = 0;
+      }
+    }
+    Ok(&self.chunk[self.pos..])
+  }
+  fn consume(&mut self, amt: usize) {
+    self.pos += amt;
+  }
+}
+
+struct Row {
+  start: usize,
+  fields: i16
+}
+impl Row {
+  fn new(buf: &[u8]) -> Row {
+    Row { start: buf.len(), fields: 0 }
+  }
+  fn field(&mut self, buf: &mut Vec<u8>, value: &str, column: Option<&Column>, settings: &Settings) {
+    if self.fields > 0 && settings.format != Format::Binary { buf.push(settings.delimiter); }
+    self.fields += 1;
+    match settings.format {
+      Format::Text if value.is_empty() => buf.extend_from_slice(b"\\N"),
+      Format::Csv if value.contains([settings.delimiter as char, '"', '\n', '\r']) || value == "\\." => {
+        buf.push(b'"');
+        buf.extend_from_slice(value.cow_replace("\"", "\"\"").as_bytes());
+        buf.push(b'"');
+      },
+      Format::Binary if value.is_empty() => buf.extend_from_slice(&(-1i32).to_be_bytes()),
+      Format::Binary => {
+        let bintype = column.map(|c| c.bintype).unwrap_or(BinaryType::Int4); // Fields without a column are generated ids
+        let lenpos = buf.len();
+        buf.extend_from_slice(&[0; 4]);
+        if !encode_binary(buf, value, bintype) {
+          fatalerr!("Error: value '{}'{} cannot be converted to binary type {}", value, column.map(|c| format!(" in column {}", c.name)).unwrap_or_default(), column.map(|c| c.datatype.as_str()).unwrap_or("integer"));
+        }
+        let len = (buf.len()-lenpos-4) as i32;
+        buf[lenpos..lenpos+4].copy_from_slice(&len.to_be_bytes());
+      },
+      _ => buf.extend_from_slice(value.as_bytes()) // An unquoted empty value is NULL in CSV
+    }
+  }
+  fn end(self, buf: &mut Vec<u8>, settings: &Settings) {
+    match settings.format {
+      Format::Binary => { buf.splice(self.start..self.start, self.fields.to_be_bytes()); }, // Tuples start with their field count
+      _ => buf.push(b'\n')
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 enum Step {
   Next,
   Repeat,
@@ -198,20 +658,68 @@ enum Step {
   Apply,
   Done
 }
+struct Fork<'a, 'b> { // Another subtable starting from the same element as the current table, with its own processing state
+  base: usize, // Length of the table stack while the subtable is active
+  tables: Vec<&'b Table<'a>>,
+  table: &'b Table<'a>,
+  parentcol: Option<&'b Column<'a>>,
+  deferred: Option<String>,
+  deferrals: Vec<Deferral<'a, 'b>>,
+  filtered: bool,
+  concattext: bool,
+  xmltotext: bool,
+  text: String,
+  gmltoewkb: bool,
+  gmlpos: bool,
+  gmlcoll: Vec<Geometry>
+}
+impl<'a, 'b> Fork<'a, 'b> {
+  fn new(tables: Vec<&'b Table<'a>>, column: &'b Column<'a>) -> Fork<'a, 'b> {
+    Fork { base: tables.len(), tables, table: column.subtable.as_ref().unwrap(), parentcol: Some(column), deferred: None, deferrals: Vec::new(), filtered: false, concattext: false, xmltotext: false, text: String::new(), gmltoewkb: false, gmlpos: false, gmlcoll: Vec::new() }
+  }
+  fn swap(&mut self, state: &mut State<'a, 'b>) {
+    mem::swap(&mut self.tables, &mut state.tables);
+    mem::swap(&mut self.table, &mut state.table);
+    mem::swap(&mut self.parentcol, &mut state.parentcol);
+    mem::swap(&mut self.deferred, &mut state.deferred);
+    mem::swap(&mut self.deferrals, &mut state.deferrals);
+    mem::swap(&mut self.filtered, &mut state.filtered);
+    mem::swap(&mut self.concattext, &mut state.concattext);
+    mem::swap(&mut self.xmltotext, &mut state.xmltotext);
+    mem::swap(&mut self.text, &mut state.text);
+    mem::swap(&mut self.gmltoewkb, &mut state.gmltoewkb);
+    mem::swap(&mut self.gmlpos, &mut state.gmlpos);
+    mem::swap(&mut self.gmlcoll, &mut state.gmlcoll);
+  }
+}
+struct Deferral<'a, 'b> { // Events of subtables that precede the key of their parent row
+  table: &'b Table<'a>,
+  events: Vec<(String, Event<'static>)> // With the path at each event
+}
 struct State<'a, 'b> {
   settings: Settings,
   reader: Reader<Box<dyn BufRead>>,
+  filename: String,
+  offset: u64, // Input bytes skipped before the reader, when resuming
+  nsbindings: Vec<(usize, String, String)>,
+  maintables: &'b [Table<'a>],
   tables: Vec<&'b Table<'a>>,
   table: &'b Table<'a>,
-  rowpath: String,
   path: String,
   parentcol: Option<&'b Column<'a>>,
-  deferred: Option<String>,
+  forks: Vec<Fork<'a, 'b>>, // The other active subtables
+  deferred: Option<String>, // Path of the subtable element whose events are being deferred
+  deferrals: Vec<Deferral<'a, 'b>>, // Innermost last
+  replay: Option<&'b Table<'a>>, // Table whose deferred events are being applied
   filtered: bool,
   skipped: bool,
   fullcount: u64,
   filtercount: u64,
   skipcount: u64,
+  rejectcount: u64,
+  nullcount: u64,
+  rejects: Option<File>, // Rows that failed processing, with the reason and their position in the input
+  quarantined: u64,
   concattext: bool,
   xmltotext: bool,
   text: String,
@@ -222,6 +730,12 @@ struct State<'a, 'b> {
   step: Step
 }
 
+impl State<'_, '_> {
+  fn position(&self) -> u64 { // Byte offset in the (decompressed) input
+    self.offset + self.reader.buffer_position()
+  }
+}
+
 fn gml_to_ewkb(cell: &RefCell<String>, coll: &[Geometry], bbox: Option<&BBox>, multitype: bool, settings: &Settings) -> bool {
   let mut ewkb: Vec<u8> = vec![];
 
@@ -237,7 +751,7 @@ fn gml_to_ewkb(cell: &RefCell<String>, coll: &[Geometry], bbox: Option<&BBox>, m
       2 => 32, // Indicate EWKB where the srid follows this byte
       3 => 32 | 128, // Add bit to indicate the presence of Z values
       _ => {
-        if !settings.hush_warning { eprintln!("Warning: GML number of dimensions {} not supported", geom.dims); }
+        warning!(settings.hush_warning, "gml", "Warning: GML number of dimensions {} not supported", geom.dims);
         32
       }
     };
@@ -290,8 +804,237 @@ fn gml_to_ewkb(cell: &RefCell<String>, coll: &[Geometry], bbox: Option<&BBox>, m
   true
 }
 
+fn binary_type(datatype: &str, colname: &str, settings: &Settings) -> BinaryType {
+  if settings.format != Format::Binary { return BinaryType::Text; }
+  lookup_binary_type(datatype).unwrap_or_else(|| fatalerr!("Error: column {} has type '{}' which is not supported with format binary", colname, datatype))
+}
+fn lookup_binary_type(datatype: &str) -> Option<BinaryType> {
+  lazy_static! {
+    static ref RE: Regex = Regex::new(r"\s*\([^)]*\)").unwrap(); // Type modifiers like varchar(20) or numeric(10,2)
+  }
+  Some(match RE.replace_all(&datatype.to_ascii_lowercase(), "").trim() {
+    "text" | "varchar" | "character varying" | "char" | "character" | "bpchar" | "name" | "citext" | "json" | "xml" => BinaryType::Text,
+    "jsonb" => BinaryType::Jsonb,
+    "boolean" | "bool" => BinaryType::Bool,
+    "smallint" | "int2" | "smallserial" | "serial2" => BinaryType::Int2,
+    "integer" | "int" | "int4" | "serial" | "serial4" => BinaryType::Int4,
+    "bigint" | "int8" | "bigserial" | "serial8" => BinaryType::Int8,
+    "real" | "float4" => BinaryType::Float4,
+    "double precision" | "float8" | "float" => BinaryType::Float8,
+    "numeric" | "decimal" => BinaryType::Numeric,
+    "date" => BinaryType::Date,
+    "timestamp" | "timestamp without time zone" => BinaryType::Timestamp,
+    "timestamptz" | "timestamp with time zone" => BinaryType::TimestampTz,
+    "bytea" => BinaryType::Bytea,
+    "uuid" => BinaryType::Uuid,
+    "geometry" | "geography" => BinaryType::Geometry,
+    _ => return None
+  })
+}
+fn encode_binary(buf: &mut Vec<u8>, value: &str, bintype: BinaryType) -> bool {
+  match bintype {
+    BinaryType::Text => buf.extend_from_slice(value.as_bytes()),
+    BinaryType::Jsonb => {
+      buf.push(1); // Version of the jsonb binary format
+      buf.extend_from_slice(value.as_bytes());
+    },
+    BinaryType::Bool => match value.trim().to_ascii_lowercase().as_str() {
+      "t" | "true" | "y" | "yes" | "on" | "1" => buf.push(1),
+      "f" | "false" | "n" | "no" | "off" | "0" => buf.push(0),
+      _ => return false
+    },
+    BinaryType::Int2 => match value.trim().parse::<i16>() {
+      Ok(int) => buf.extend_from_slice(&int.to_be_bytes()),
+      Err(_) => return false
+    },
+    BinaryType::Int4 => match value.trim().parse::<i32>() {
+      Ok(int) => buf.extend_from_slice(&int.to_be_bytes()),
+      Err(_) => return false
+    },
+    BinaryType::Int8 => match value.trim().parse::<i64>() {
+      Ok(int) => buf.extend_from_slice(&int.to_be_bytes()),
+      Err(_) => return false
+    },
+    BinaryType::Float4 => match value.trim().parse::<f32>() {
+      Ok(float) => buf.extend_from_slice(&float.to_be_bytes()),
+      Err(_) => return false
+    },
+    BinaryType::Float8 => match value.trim().parse::<f64>() {
+      Ok(float) => buf.extend_from_slice(&float.to_be_bytes()),
+      Err(_) => return false
+    },
+    BinaryType::Numeric => return encode_numeric(buf, value.trim()),
+    BinaryType::Date => match value.trim() {
+      "infinity" => buf.extend_from_slice(&i32::MAX.to_be_bytes()),
+      "-infinity" => buf.extend_from_slice(&i32::MIN.to_be_bytes()),
+      value => match parse_timestamp(value) {
+        Some((days, _, _)) => buf.extend_from_slice(&(days as i32).to_be_bytes()),
+        None => return false
+      }
+    },
+    BinaryType::Timestamp | BinaryType::TimestampTz => match value.trim() {
+      "infinity" => buf.extend_from_slice(&i64::MAX.to_be_bytes()),
+      "-infinity" => buf.extend_from_slice(&i64::MIN.to_be_bytes()),
+      value => match parse_timestamp(value) {
+        Some((days, micros, offset)) => {
+          let mut stamp = days*86_400_000_000 + micros;
+          if bintype == BinaryType::TimestampTz { stamp -= offset*1_000_000; } // Without an offset, the value is taken as UTC
+          buf.extend_from_slice(&stamp.to_be_bytes());
+        },
+        None => return false
+      }
+    },
+    BinaryType::Bytea => match value.strip_prefix("\\x") {
+      Some(hex) => return decode_hex(buf, hex),
+      None => buf.extend_from_slice(value.as_bytes())
+    },
+    BinaryType::Uuid => {
+      let hex = value.trim().trim_matches(['{', '}']).cow_replace("-", "");
+      if hex.len() != 32 { return false; }
+      return decode_hex(buf, &hex);
+    },
+    BinaryType::Geometry => return decode_hex(buf, value.trim()) // Hex EWKB (as produced by gml-to-ewkb) goes out as raw bytes
+  }
+  true
+}
+fn encode_numeric(buf: &mut Vec<u8>, value: &str) -> bool {
+  lazy_static! {
+    static ref RE: Regex = Regex::new(r"^([+-]?)([0-9]*)(?:\.([0-9]*))?(?:[eE]([+-]?[0-9]{1,5}))?$").unwrap();
+  }
+  if value.eq_ignore_ascii_case("nan") {
+    buf.extend_from_slice(&[0, 0, 0, 0, 0xc0, 0, 0, 0]);
+    return true;
+  }
+  let caps = match RE.captures(value) {
+    Some(caps) => caps,
+    None => return false
+  };
+  let int = caps.get(2).map_or("", |m| m.as_str());
+  let frac = caps.get(3).map_or("", |m| m.as_str());
+  if int.is_empty() && frac.is_empty() { return false; }
+  let exp: i64 = caps.get(4).map_or(0, |m| m.as_str().parse().unwrap());
+  let dscale = (frac.len() as i64 - exp).max(0);
+  if dscale > 0x3fff { return false; } // Beyond the scale PostgreSQL accepts
+  // Align the digits on the decimal point in groups of four, as numeric uses base 10000
+  let mut digits = format!("{}{}", int, frac);
+  let mut point = int.len() as i64 + exp;
+  let lead = (-point).max(0) + (4 - point.max(0)%4)%4;
+  digits.insert_str(0, &"0".repeat(lead as usize));
+  point += lead;
+  if (digits.len() as i64) < point { digits.push_str(&"0".repeat((point - digits.len() as i64) as usize)); }
+  digits.push_str(&"0".repeat((4 - digits.len()%4)%4));
+  let mut groups = digits.as_bytes().chunks(4).map(|chunk| chunk.iter().fold(0i16, |acc, d| acc*10 + (d - b'0') as i16)).collect::<Vec<i16>>();
+  let mut weight = point/4 - 1;
+  let zeros = groups.iter().take_while(|g| **g == 0).count();
+  groups.drain(..zeros);
+  weight -= zeros as i64;
+  while groups.last() == Some(&0) { groups.pop(); }
+  if groups.is_empty() { weight = 0; }
+  let sign: u16 = if &caps[1] == "-" && !groups.is_empty() { 0x4000 } else { 0 };
+  buf.extend_from_slice(&(groups.len() as i16).to_be_bytes());
+  buf.extend_from_slice(&(weight as i16).to_be_bytes());
+  buf.extend_from_slice(&sign.to_be_bytes());
+  buf.extend_from_slice(&(dscale as u16).to_be_bytes());
+  for group in groups { buf.extend_from_slice(&group.to_be_bytes()); }
+  true
+}
+fn parse_timestamp(value: &str) -> Option<(i64, i64, i64)> { // Returns days and microseconds since 2000-01-01 and the UTC offset in seconds
+  lazy_static! {
+    static ref RE: Regex = Regex::new(r"^(-?[0-9]{4,})-([0-9]{2})-([0-9]{2})(?:[T ]([0-9]{2}):([0-9]{2})(?::([0-9]{2})(?:\.([0-9]{1,6})[0-9]*)?)?)? ?(Z|[+-][0-9]{2}(?::?[0-9]{2})?)?$").unwrap();
+  }
+  let caps = RE.captures(value)?;
+  let num = |i: usize| caps.get(i).map_or(0, |m| m.as_str().parse::<i64>().unwrap());
+  let (year, month, day) = (num(1), num(2), num(3));
+  let leap = (year%4 == 0 && year%100 != 0) || year%400 == 0;
+  let monthdays = [31, if leap { 29 } else { 28 }, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+  if !(1..=12).contains(&month) || day < 1 || day > monthdays[month as usize - 1] { return None; }
+  if num(4) > 23 || num(5) > 59 || num(6) > 60 { return None; }
+  // Days from civil date (proleptic Gregorian calendar), shifted to the PostgreSQL epoch
+  let y = if month <= 2 { year-1 } else { year };
+  let era = if y >= 0 { y } else { y-399 }/400;
+  let yoe = y - era*400;
+  let doy = (153*(if month > 2 { month-3 } else { month+9 }) + 2)/5 + day - 1;
+  let days = era*146097 + yoe*365 + yoe/4 - yoe/100 + doy - 730425;
+  let fraction = caps.get(7).map_or(0, |m| format!("{:0<6}", m.as_str()).parse::<i64>().unwrap());
+  let micros = (num(4)*3600 + num(5)*60 + num(6))*1_000_000 + fraction;
+  let offset = match caps.get(8).map(|m| m.as_str()) {
+    None | Some("Z") => 0,
+    Some(tz) => {
+      let digits = tz[1..].cow_replace(":", "");
+      let secs = digits[0..2].parse::<i64>().unwrap()*3600 + digits.get(2..4).map_or(0, |m| m.parse::<i64>().unwrap()*60);
+      if tz.starts_with('-') { -secs } else { secs }
+    }
+  };
+  Some((days, micros, offset))
+}
+fn serial_offset(value: &Yaml) -> Option<u64> { // The id that 'seri' numbering continues after; 'true' starts from 1
+  match value {
+    Yaml::Boolean(true) => Some(0),
+    Yaml::Integer(offset) if *offset >= 0 => Some(*offset as u64),
+    _ => None
+  }
+}
+fn mismatch_policy(value: &Yaml) -> Option<Mismatch> {
+  match value {
+    Yaml::BadValue => None,
+    Yaml::Null => Some(Mismatch::Null), // YAML reads an unquoted null as such
+    Yaml::String(value) if value == "error" => Some(Mismatch::Error),
+    Yaml::String(value) if value == "null" => Some(Mismatch::Null),
+    Yaml::String(value) if value == "skip" => Some(Mismatch::Skip),
+    Yaml::String(value) => fatalerr!("Error: invalid 'fail' setting in configuration file: {}; should be error, null or skip", value),
+    _ => fatalerr!("Error: invalid 'fail' setting in configuration file; should be error, null or skip")
+  }
+}
+fn check_value(value: &str, bintype: BinaryType, formats: &[&str]) -> Option<String> { // Returns the value as it should be written, or None when it doesn't match the type
+  let value = value.trim();
+  match bintype {
+    BinaryType::Bool => match value.to_ascii_lowercase().as_str() {
+      "t" | "true" | "y" | "yes" | "on" | "1" => Some(String::from("t")),
+      "f" | "false" | "n" | "no" | "off" | "0" => Some(String::from("f")),
+      _ => None
+    },
+    BinaryType::Int2 => value.parse::<i16>().ok().map(|int| int.to_string()),
+    BinaryType::Int4 => value.parse::<i32>().ok().map(|int| int.to_string()),
+    BinaryType::Int8 => value.parse::<i64>().ok().map(|int| int.to_string()),
+    BinaryType::Float4 | BinaryType::Float8 => value.parse::<f64>().ok().map(|_| value.to_owned()),
+    BinaryType::Numeric => encode_numeric(&mut Vec::new(), value).then(|| value.to_owned()),
+    _ if value == "infinity" || value == "-infinity" => Some(value.to_owned()),
+    _ if formats.is_empty() => parse_timestamp(value).map(|_| value.to_owned()),
+    _ => formats.iter().find_map(|format| parse_datetime(value, format, bintype))
+  }
+}
+fn parse_datetime(value: &str, format: &str, bintype: BinaryType) -> Option<String> { // Rewrites a date or time in the given format to ISO 8601
+  let output = match bintype {
+    BinaryType::Date => "%Y-%m-%d",
+    _ => "%Y-%m-%d %H:%M:%S%.f"
+  };
+  if let Ok(stamp) = DateTime::parse_from_str(value, format) {
+    return Some(match bintype {
+      BinaryType::TimestampTz => stamp.format("%Y-%m-%d %H:%M:%S%.f%:z").to_string(),
+      _ => stamp.naive_local().format(output).to_string() // Like PostgreSQL, ignore the offset for types without time zone
+    });
+  }
+  if let Ok(stamp) = NaiveDateTime::parse_from_str(value, format) { return Some(stamp.format(output).to_string()); }
+  NaiveDate::parse_from_str(value, format).ok().map(|date| date.and_hms_opt(0, 0, 0).unwrap().format(output).to_string())
+}
+fn decode_hex(buf: &mut Vec<u8>, hex: &str) -> bool {
+  if !hex.len().is_multiple_of(2) { return false; }
+  for pair in hex.as_bytes().chunks(2) {
+    match u8::from_str_radix(std::str::from_utf8(pair).unwrap_or(""), 16) {
+      Ok(byte) => buf.push(byte),
+      Err(_) => return false
+    }
+  }
+  true
+}
+
 fn add_table<'a>(name: &str, rowpath: &str, outfile: Option<&str>, settings: &Settings, colspec: &'a [Yaml], cardinality: Cardinality) -> Table<'a> {
   let mut table = Table::new(name, rowpath, outfile, settings, cardinality);
+  let key = colspec.iter().enumerate().filter(|(_, col)| col["pkey"].as_bool() == Some(true)).map(|(i, _)| i).collect::<Vec<usize>>();
+  if !key.is_empty() {
+    if cardinality == Cardinality::ManyToOne && key.len() > 1 { fatalerr!("Error: table '{}' is normalized into its parent, so it cannot have a key of multiple columns; use a 'seri' column instead", name); }
+    table.key = key;
+  }
   for col in colspec {
     let colname = col["name"].as_str().unwrap_or_else(|| fatalerr!("Error: column has no 'name' entry in configuration file"));
     let fkey = col["fkey"].as_str().map(String::from).map(|v| { match v.split_once('.') {
@@ -299,39 +1042,85 @@ fn add_table<'a>(name: &str, rowpath: &str, outfile: Option<&str>, settings: &Se
       None => fatalerr!("Error: column {} option 'fkey' is invalid", colname)
     }});
     let colpath =
-      if let Some(true) = col["seri"].as_bool() { "/" }
-      else if fkey.is_some() { "/" }
+      if serial_offset(&col["seri"]).is_some() || fkey.is_some() { "/" }
       else { col["path"].as_str().unwrap_or_else(|| fatalerr!("Error: table '{}' column '{}' has no 'path' entry in configuration file", name, colname)) };
     let mut path = String::from(&table.path);
     if !colpath.is_empty() && !colpath.starts_with('/') { path.push('/'); }
     path.push_str(colpath);
     if path.ends_with('/') { path.pop(); }
-    let serial = match col["seri"].as_bool() {
-      Some(true) => {
-        if *col != colspec[0] && !settings.hush_warning { eprintln!("Warning: a 'seri' column usually needs to be the first column; {} in table {} is not", colname, table.name); }
-        Some(Cell::new(0))
+    let serial = match serial_offset(&col["seri"]) {
+      Some(offset) => {
+        if *col != colspec[0] { warning!(settings.hush_warning, "config", "Warning: a 'seri' column usually needs to be the first column; {} in table {} is not", colname, table.name); }
+        Some(Cell::new(offset))
       },
+      None => None
+    };
+    let hashed = col["hash"].as_bool() == Some(true) && !col["norm"].is_badvalue() && col["cols"].is_badvalue(); // Ids of the domain are derived from its values
+    let idtype = if hashed { "bigint" } else { "integer" };
+    let hash = match &col["hash"] {
+      _ if hashed => None,
+      Yaml::BadValue => None,
+      hash if serial.is_some() => {
+        let names = match hash {
+          Yaml::String(source) => vec![source.as_str()],
+          Yaml::Array(sources) => sources.iter().map(|source| source.as_str().unwrap_or_else(|| fatalerr!("Error: table '{}' column '{}' option 'hash' contains an invalid entry", name, colname))).collect(),
+          _ => fatalerr!("Error: table '{}' column '{}' option 'hash' should be a column name or a list of them", name, colname)
+        };
+        let columns = names.iter().map(|source| colspec.iter().position(|c| c["name"].as_str() == Some(source) && c["cols"].is_badvalue() && serial_offset(&c["seri"]).is_none())
+          .unwrap_or_else(|| fatalerr!("Error: table '{}' column '{}' option 'hash' refers to '{}', which is not a value column of the table", name, colname, source))).collect();
+        Some(IdHash::new(format!("table {} column {}", name, colname), col["type"].as_str() == Some("uuid"), columns, settings))
+      },
+      _ => fatalerr!("Error: table '{}' column '{}' option 'hash' needs 'seri' with the columns to hash, or 'norm' with true", name, colname)
+    };
+    let mut datatype = col["type"].as_str().unwrap_or(if hash.is_some() || (serial.is_some() && settings.emit_identity) { "bigint" } else { "text" }).to_string();
+    let formats: Vec<&str> = match &col["form"] { // Input formats for date and time values
+      Yaml::BadValue => Vec::new(),
+      Yaml::String(format) => vec![format.as_str()],
+      Yaml::Array(formats) => formats.iter().map(|f| f.as_str().unwrap_or_else(|| fatalerr!("Error: column {} option 'form' contains an invalid entry", colname))).collect(),
+      _ => fatalerr!("Error: column {} option 'form' should be a format string or a list of them", colname)
+    };
+    let check = match (mismatch_policy(&col["fail"]).or(settings.mismatch), lookup_binary_type(&datatype)) {
+      _ if serial.is_some() || fkey.is_some() || !col["cols"].is_badvalue() || col["conv"].as_str().is_some() => None,
+      (None, _) if formats.is_empty() => None,
+      (mismatch, Some(bintype @ (BinaryType::Bool | BinaryType::Int2 | BinaryType::Int4 | BinaryType::Int8 | BinaryType::Float4 | BinaryType::Float8 | BinaryType::Numeric | BinaryType::Date | BinaryType::Timestamp | BinaryType::TimestampTz))) => Some((bintype, mismatch.unwrap_or(Mismatch::Error))),
       _ => None
     };
-    let mut datatype = col["type"].as_str().unwrap_or("text").to_string();
+    if !formats.is_empty() && !matches!(check, Some((BinaryType::Date | BinaryType::Timestamp | BinaryType::TimestampTz, _))) {
+      warning!(settings.hush_warning, "config", "Warning: the form option has no function on column {} of type {}", colname, datatype);
+    }
     let mut include: Option<Regex> = col["incl"].as_str().map(|str| Regex::new(str).unwrap_or_else(|err| fatalerr!("Error: invalid regex in 'incl' entry in configuration file: {}", err)));
     let mut exclude: Option<Regex> = col["excl"].as_str().map(|str| Regex::new(str).unwrap_or_else(|err| fatalerr!("Error: invalid regex in 'excl' entry in configuration file: {}", err)));
+    let strict = col["strict"].as_bool().unwrap_or(false);
     let norm = col["norm"].as_str();
     let file = col["file"].as_str();
+    let dictionary = match col["dict"].as_str() { // Read before the outputs are opened, as it may be one of them
+      Some(dict) if norm.is_some() => {
+        if settings.format == Format::Binary { fatalerr!("Error: the dict option of column {} cannot be used with format binary", colname); }
+        if norm == Some(dict) && settings.filemode == "truncate" { fatalerr!("Error: dictionary file '{}' of column {} would be overwritten by its output before it is read; use 'mode: append' or a copy of the file", dict, colname); }
+        Some((dict, open_dictionary(dict)))
+      },
+      Some(_) => {
+        warning!(settings.hush_warning, "config", "Warning: the dict option has no function without 'norm' on column {}", colname);
+        None
+      },
+      None => None
+    };
     let cardinality = match (file, norm) { // The combination of 'file' and 'norm' options determine relation to the subtable (if any)
       (None, None) => Cardinality::Default,
       (Some(_), None) => Cardinality::OneToMany,
       (None, Some(_)) => Cardinality::ManyToOne,
       (Some(_), Some(_)) => Cardinality::ManyToMany
     };
+    if !col["cols"].is_badvalue() && table.key.iter().any(|i| *i >= table.columns.len()) { fatalerr!("Error: table '{}' has key columns after subtable '{}'; they should come before it", name, colname); }
+    if col["pkey"].as_bool() == Some(true) && !col["cols"].is_badvalue() { fatalerr!("Error: table '{}' subtable column '{}' cannot be part of the key", name, colname); }
     let mut subtable: Option<Table> = match col["cols"].is_badvalue() {
       true => match cardinality { // No 'cols' setting; the current column is the only one in the subtable
         Cardinality::OneToMany => {
           let filename = col["file"].as_str().unwrap();
           if table.columns.is_empty() { fatalerr!("Error: table '{}' cannot have a subtable as first column", name); }
           let mut subtable = add_table(colname, &path, Some(filename), settings, &[], cardinality);
-          subtable.columns.push(Column { name: colname.to_string(), path: path.clone(), datatype: datatype.to_string(), include: mem::take(&mut include), exclude: mem::take(&mut exclude), ..Default::default() });
-          emit_preamble(&subtable, settings, Some(format!("{} {}", name, table.columns[0].datatype)));
+          subtable.columns.push(Column { name: colname.to_string(), path: path.clone(), datatype: datatype.to_string(), bintype: binary_type(&datatype, colname, settings), check, formats: formats.clone(), include: mem::take(&mut include), exclude: mem::take(&mut exclude), strict, ..Default::default() });
+          emit_preamble(&subtable, settings, Some(&table));
           Some(subtable)
         },
         Cardinality::ManyToMany => {
@@ -339,8 +1128,8 @@ fn add_table<'a>(name: &str, rowpath: &str, outfile: Option<&str>, settings: &Se
           if table.columns.is_empty() { fatalerr!("Error: table '{}' cannot have a subtable as first column", name); }
           let mut subtable = add_table(colname, &path, Some(filename), settings, &[], cardinality);
 //          subtable.columns.push(Column { name: String::from("id"), path: String::new(), datatype: String::from("integer"), ..Default::default() });
-          subtable.columns.push(Column { name: colname.to_string(), path: path.clone(), datatype: "integer".to_string(), include: mem::take(&mut include), exclude: mem::take(&mut exclude), ..Default::default() });
-          emit_preamble(&subtable, settings, Some(format!("{} {}", name, table.columns[0].datatype)));
+          subtable.columns.push(Column { name: colname.to_string(), path: path.clone(), datatype: idtype.to_string(), bintype: binary_type(idtype, colname, settings), check, formats: formats.clone(), include: mem::take(&mut include), exclude: mem::take(&mut exclude), strict, ..Default::default() });
+          emit_preamble(&subtable, settings, Some(&table));
           Some(subtable)
         },
         _ => None
@@ -355,14 +1144,14 @@ fn add_table<'a>(name: &str, rowpath: &str, outfile: Option<&str>, settings: &Se
           let filename = col["file"].as_str().unwrap_or_else(|| fatalerr!("Error: subtable {} has no 'file' entry", colname));
           if table.columns.is_empty() { fatalerr!("Error: table '{}' cannot have a subtable as first column", name); }
           let subtable = add_table(colname, &path, Some(filename), settings, col["cols"].as_vec().unwrap_or_else(|| fatalerr!("Error: subtable 'cols' entry is not an array")), cardinality);
-          emit_preamble(&subtable, settings, Some(format!("{} {}", name, table.columns[0].datatype)));
+          emit_preamble(&subtable, settings, Some(&table));
           Some(subtable)
         },
         _ => { // One-to-many relation (this file will contain the subtable with the parent table fkey)
           let filename = col["file"].as_str().unwrap_or_else(|| fatalerr!("Error: subtable {} has no 'file' entry", colname));
           if table.columns.is_empty() { fatalerr!("Error: table '{}' cannot have a subtable as first column", name); }
           let subtable = add_table(colname, &path, Some(filename), settings, col["cols"].as_vec().unwrap_or_else(|| fatalerr!("Error: subtable 'cols' entry is not an array")), cardinality);
-          emit_preamble(&subtable, settings, Some(format!("{} {}", name, table.columns[0].datatype)));
+          emit_preamble(&subtable, settings, Some(&table));
           Some(subtable)
         }
       }
@@ -383,23 +1172,39 @@ fn add_table<'a>(name: &str, rowpath: &str, outfile: Option<&str>, settings: &Se
           None => Some(filename)                        // Many-to-one relation (single column) with auto serial; rows go into this file
         };
         let mut domain = Domain::new(colname, file, settings);
+        if let Some(serial) = subtable.as_ref().and_then(|table| table.columns.first()).and_then(|c| c.serial.as_ref()) { domain.lastid = serial.get(); } // Normalized rows are numbered by the domain
+        if hashed { domain.hash = Some(IdHash::new(format!("table {} column {}", name, colname), false, Vec::new(), settings)); }
         if file.is_some() {
           if subtable.is_some() && !col["cols"].is_badvalue() {
             for col in col["cols"].as_vec().unwrap() {
               let colname = col["name"].as_str().unwrap_or_else(|| fatalerr!("Error: column has no 'name' entry in configuration file"));
               let datatype = col["type"].as_str().unwrap_or("text");
-              domain.table.columns.push(Column { name: colname.to_string(), path: String::new(), datatype: datatype.to_string(), ..Default::default() });
+              domain.table.columns.push(Column { name: colname.to_string(), path: String::new(), datatype: datatype.to_string(), bintype: binary_type(datatype, colname, settings), ..Default::default() });
             }
           }
           else {
-            domain.table.columns.push(Column { name: String::from("id"), path: String::new(), datatype: String::from("integer"), ..Default::default() });
-            domain.table.columns.push(Column { name: colname.to_string(), path: String::new(), datatype, ..Default::default() });
+            domain.table.columns.push(Column { name: String::from("id"), path: String::new(), datatype: idtype.to_string(), bintype: binary_type(idtype, "id", settings), ..Default::default() });
+            domain.table.columns.push(Column { name: colname.to_string(), path: String::new(), bintype: binary_type(&datatype, colname, settings), datatype, ..Default::default() });
           }
           emit_preamble(&domain.table, settings, None);
         }
-        datatype = String::from("integer");
+        if let Some((dict, (reader, copy))) = dictionary {
+          let rows = read_dictionary(dict, reader, copy, settings);
+          match subtable.as_ref() {
+            Some(table) if !col["cols"].is_badvalue() && table.columns[0].serial.is_none() => { // Rows without an id; find the key among the written columns
+              let written = table.columns.iter().enumerate().filter(|(_, c)| !c.hide && c.subtable.is_none()).map(|(i, _)| i).collect::<Vec<usize>>();
+              let fields = table.key.iter().map(|k| written.iter().position(|i| i == k).unwrap_or_else(|| fatalerr!("Error: key column of table '{}' is hidden, so it cannot be read from dictionary file '{}'", colname, dict))).collect::<Vec<usize>>();
+              domain.load(dict, rows, Some(&fields));
+            },
+            _ => domain.load(dict, rows, None)
+          }
+        }
+        datatype = match subtable.as_ref() {
+          Some(table) if table.columns.first().is_some_and(|c| c.hash.is_some()) => table.columns[0].datatype.clone(),
+          _ => idtype.to_string()
+        };
         if let Some(ref mut table) = subtable { // Push the domain down to the subtable
-          table.domain = Box::new(Some(RefCell::new(domain)));
+          *table.domain = Some(RefCell::new(domain));
           None
         }
         else { Some(RefCell::new(domain)) }
@@ -410,7 +1215,7 @@ fn add_table<'a>(name: &str, rowpath: &str, outfile: Option<&str>, settings: &Se
     let multitype = col["mult"].as_bool().unwrap_or(false);
 
     if let Some(val) = convert {
-      if !vec!("xml-to-text", "gml-to-ewkb", "concat-text").contains(&val) {
+      if !["xml-to-text", "gml-to-ewkb", "concat-text"].contains(&val) {
         fatalerr!("Error: table '{}' option 'conv' contains invalid value: {}", name, val);
       }
       if val == "gml-to-ewkb" && !settings.hush_notice {
@@ -421,7 +1226,7 @@ fn add_table<'a>(name: &str, rowpath: &str, outfile: Option<&str>, settings: &Se
       }
     }
     if let Some(val) = aggr {
-      if !vec!("first", "last", "append").contains(&val) {
+      if !["first", "last", "append"].contains(&val) {
         fatalerr!("Error: table '{}' option 'aggr' contains invalid value: {}", name, val);
       }
     }
@@ -436,27 +1241,34 @@ fn add_table<'a>(name: &str, rowpath: &str, outfile: Option<&str>, settings: &Se
         eprintln!("Notice: when using filtering (incl/excl) and aggregation on a single column, the filter is checked after aggregation");
       }
     }
-    if bbox.is_some() && (convert.is_none() || convert.unwrap() != "gml-to-ewkb") && !settings.hush_warning {
-      eprintln!("Warning: the bbox option has no function without conversion type 'gml-to-ekwb'");
+    if strict && include.is_none() && exclude.is_none() && (subtable.is_none() || col["cols"].is_badvalue()) {
+      warning!(settings.hush_warning, "config", "Warning: the strict option has no function without filtering (incl/excl)");
+    }
+    if bbox.is_some() && (convert.is_none() || convert.unwrap() != "gml-to-ewkb") {
+      warning!(settings.hush_warning, "config", "Warning: the bbox option has no function without conversion type 'gml-to-ekwb'");
     }
 
-    let column = Column { name: colname.to_string(), path, serial, fkey, datatype, attr, hide, include, exclude, trim, convert, find, replace, aggr, subtable, domain, bbox, multitype, ..Default::default() };
+    let bintype = binary_type(&datatype, colname, settings);
+    let check = if subtable.is_some() { None } else { check }; // Single column subtables check the value themselves
+    let column = Column { name: colname.to_string(), path, serial, fkey, datatype, bintype, check, formats, attr, hide, include, exclude, strict, trim, convert, find, replace, aggr, subtable, domain, hash, bbox, multitype, ..Default::default() };
     table.columns.push(column);
   }
 
   table
 }
-fn emit_preamble(table: &Table, settings: &Settings, fkey: Option<String>) {
-  if settings.emit_starttransaction {
-    write!(table.buf.borrow_mut(), "START TRANSACTION;\n").unwrap();
+fn emit_preamble(table: &Table, settings: &Settings, parent: Option<&Table>) {
+  let fkey = parent.map(|parent| parent.key_columns());
+  let database = settings.connection.is_some(); // The statements up to and including the COPY are run on the connection
+  let mut sql = String::new();
+  if settings.emit_starttransaction && !database {
+    writeln!(sql, "START TRANSACTION;").unwrap();
   }
   if settings.emit_droptable {
-    write!(table.buf.borrow_mut(), "DROP TABLE IF EXISTS {};\n", table.name).unwrap();
+    writeln!(sql, "DROP TABLE IF EXISTS {};", table.name).unwrap();
   }
   if settings.emit_createtable {
     if table.cardinality == Cardinality::ManyToMany {
-      let fkey = fkey.as_ref().unwrap();
-      write!(table.buf.borrow_mut(), "CREATE TABLE IF NOT EXISTS {}_{} ({}, {} {});\n", fkey.split_once(' ').unwrap().0, table.name, fkey, table.name, if table.columns.is_empty() { "integer" } else { &table.columns[0].datatype }).unwrap();
+      writeln!(sql, "CREATE TABLE IF NOT EXISTS {}_{} ({}, {});", parent.unwrap().name, table.name, fkey.as_ref().unwrap().join(", "), table.key_columns().join(", ")).unwrap();
     }
     else {
       let mut cols = table.columns.iter().filter_map(|c| {
@@ -464,47 +1276,151 @@ fn emit_preamble(table: &Table, settings: &Settings, fkey: Option<String>) {
         let mut spec = String::from(&c.name);
         spec.push(' ');
         spec.push_str(&c.datatype);
+        if settings.emit_identity && c.serial.is_some() && c.hash.is_none() && !c.datatype.contains("serial") && lookup_binary_type(&c.datatype).is_some_and(|t| matches!(t, BinaryType::Int2 | BinaryType::Int4 | BinaryType::Int8)) {
+          spec.push_str(" GENERATED BY DEFAULT AS IDENTITY");
+        }
         Some(spec)
       }).collect::<Vec<String>>().join(", ");
-      if fkey.is_some() { cols.insert_str(0, &format!("{}, ", fkey.as_ref().unwrap())); }
-      write!(table.buf.borrow_mut(), "CREATE TABLE IF NOT EXISTS {} ({});\n", table.name, cols).unwrap();
+      if let Some(fkey) = &fkey { cols.insert_str(0, &format!("{}, ", fkey.join(", "))); }
+      writeln!(sql, "CREATE TABLE IF NOT EXISTS {} ({});", table.name, cols).unwrap();
     }
   }
   if settings.emit_truncate {
-    write!(table.buf.borrow_mut(), "TRUNCATE {};\n", table.name).unwrap();
+    writeln!(sql, "TRUNCATE {};", table.name).unwrap();
   }
-  if settings.emit_copyfrom {
-    if table.cardinality == Cardinality::ManyToMany {
-      let parent = fkey.as_ref().unwrap().split_once(' ').unwrap().0;
-      write!(table.buf.borrow_mut(), "COPY {}_{} ({}, {}) FROM stdin;\n", parent, table.name, parent, table.name).unwrap();
-    }
-    else {
-      let cols = table.columns.iter().filter_map(|c| {
+  let (copyname, copycols) = match table.cardinality {
+    Cardinality::ManyToMany => {
+      let cols = fkey.unwrap().into_iter().chain(table.key_columns()).map(|col| col.split(' ').next().unwrap().to_owned()).collect();
+      (format!("{}_{}", parent.unwrap().name, table.name), cols)
+    },
+    _ => {
+      let mut cols = fkey.into_iter().flatten().map(|col| col.split(' ').next().unwrap().to_owned()).collect::<Vec<String>>();
+      cols.extend(table.columns.iter().filter_map(|c| {
         if c.hide || (c.subtable.is_some() && c.subtable.as_ref().unwrap().cardinality != Cardinality::ManyToOne) { return None; }
         Some(String::from(&c.name))
-      }).collect::<Vec<String>>().join(", ");
-      if fkey.is_some() {
-        write!(table.buf.borrow_mut(), "COPY {} ({}, {}) FROM stdin;\n", table.name, fkey.unwrap().split(' ').next().unwrap(), cols).unwrap();
-      }
-      else { write!(table.buf.borrow_mut(), "COPY {} ({}) FROM stdin;\n", table.name, cols).unwrap(); }
+      }));
+      (table.name.clone(), cols)
+    }
+  };
+  if settings.emit_copyfrom || database {
+    let options = match settings.format {
+      Format::Text => String::new(),
+      Format::Binary => String::from(" WITH (FORMAT binary)"),
+      Format::Csv => format!(" WITH (FORMAT csv{}{})",
+        if settings.delimiter == b',' { String::new() } else { format!(", DELIMITER '{}'", (settings.delimiter as char).to_string().cow_replace("'", "''")) },
+        if settings.header { ", HEADER true" } else { "" }
+      )
+    };
+    writeln!(sql, "COPY {} ({}) FROM stdin{};", copyname, copycols.join(", "), options).unwrap();
+  }
+  if settings.explain {
+    table.preamble.replace(sql);
+    return;
+  }
+  if settings.resume { return; } // The statements and headers are in the output already
+  table.buf.borrow_mut().extend_from_slice(sql.as_bytes());
+  if database { table.flush(); } // Keeps the statements separate from the data
+  if settings.format == Format::Csv && settings.header {
+    let mut buf = table.buf.borrow_mut();
+    let mut row = Row::new(&buf);
+    for col in copycols.iter() {
+      row.field(&mut buf, col, None, settings);
     }
+    row.end(&mut buf, settings);
+  }
+  if settings.format == Format::Binary { // Binary COPY header: signature, flags field and header extension length
+    let mut buf = table.buf.borrow_mut();
+    buf.extend_from_slice(b"PGCOPY\n\xff\r\n\0");
+    buf.extend_from_slice(&0u32.to_be_bytes());
+    buf.extend_from_slice(&0u32.to_be_bytes());
   }
   table.flush();
 }
 
-fn main() {
-  let args: Vec<_> = env::args().collect();
-  let bufread: Box<dyn BufRead>;
-  if args.len() == 2 {
-    bufread = Box::new(BufReader::new(stdin()));
+fn explain_table(table: &Table, settings: &Settings, depth: usize) {
+  let indent = "  ".repeat(depth);
+  let output = match (&settings.connection, &table.file) {
+    _ if table.cardinality == Cardinality::None => String::from("none (lookup only)"),
+    (Some(_), _) => String::from("database connection"),
+    (None, Some(file)) => file.clone(),
+    (None, None) => String::from("stdout")
+  };
+  println!("{}Table {} ({:?})", indent, table.name, table.cardinality);
+  if table.path != "/_domain_" { println!("{}  path: {}", indent, table.path); }
+  println!("{}  output: {}", indent, output);
+  for col in &table.columns {
+    let mut notes = Vec::new();
+    if let Some(attr) = col.attr { notes.push(format!("attribute {}", attr)); }
+    if let Some(serial) = &col.serial { notes.push(match serial.get() { 0 => String::from("serial"), offset => format!("serial after {}", offset) }); }
+    if let Some((table, column)) = &col.fkey { notes.push(format!("fkey {}.{}", table, column)); }
+    if col.hide { notes.push(String::from("hidden")); }
+    if let Some(convert) = col.convert { notes.push(format!("conv {}", convert)); }
+    if let Some(aggr) = col.aggr { notes.push(format!("aggr {}", aggr)); }
+    if let Some(include) = &col.include { notes.push(format!("incl {}", include)); }
+    if let Some(exclude) = &col.exclude { notes.push(format!("excl {}", exclude)); }
+    if col.strict { notes.push(String::from("strict")); }
+    if let Some(find) = &col.find { notes.push(format!("find {} repl {}", find, col.replace.unwrap_or(""))); }
+    if col.trim { notes.push(String::from("trim")); }
+    if let Some((_, mismatch)) = col.check { notes.push(format!("fail {}", format!("{:?}", mismatch).to_lowercase())); }
+    if !col.formats.is_empty() { notes.push(format!("form {}", col.formats.join(" | "))); }
+    if col.domain.is_some() { notes.push(String::from("normalized")); }
+    if let Some(subtable) = &col.subtable { notes.push(format!("subtable {}", subtable.name)); }
+    let path = if col.path.is_empty() { String::new() } else { format!(" {}", col.path) };
+    println!("{}  - {} {}{}{}", indent, col.name, col.datatype, path, if notes.is_empty() { String::new() } else { format!(" ({})", notes.join(", ")) });
+  }
+  if !table.preamble.borrow().is_empty() {
+    println!("{}  statements:", indent);
+    for line in table.preamble.borrow().lines() { println!("{}    {}", indent, line); }
   }
-  else if args.len() == 3 {
-    bufread = Box::new(BufReader::new(File::open(&args[2]).unwrap_or_else(|err| fatalerr!("Error: failed to open input file '{}': {}", args[2], err))));
+  for col in &table.columns {
+    if let Some(domain) = &col.domain { explain_table(&domain.borrow().table, settings, depth+1); }
+    if let Some(subtable) = &col.subtable {
+      explain_table(subtable, settings, depth+1);
+      if let Some(domain) = subtable.domain.as_ref() { explain_table(&domain.borrow().table, settings, depth+2); }
+    }
+  }
+}
+
+fn main() {
+  let mut args = Vec::new();
+  let mut check = false;
+  let mut explain = false;
+  let mut stats = None;
+  let mut resume = false;
+  let mut argv = env::args();
+  while let Some(arg) = argv.next() {
+    match arg.as_str() {
+      "--check" => check = true,
+      "--explain" => explain = true,
+      "--resume" => resume = true,
+      "--stats" => stats = Some(argv.next().unwrap_or_else(|| fatalerr!("Error: option --stats needs a file name"))),
+      opt if opt.starts_with("--") => fatalerr!("Error: unknown option {}", opt),
+      _ => args.push(arg)
+    }
   }
-  else {
+  if args.len() < 2 {
     eprintln!("xml-to-postgres {}", git_version!(args = ["--always", "--tags", "--dirty=-modified"]));
-    fatalerr!("Usage: {} <configfile> [xmlfile]", args[0]);
+    eprintln!("Usage: {} [--check|--explain|--resume|--stats <statsfile>] <configfile> [xmlfile...]", args[0]);
+    eprintln!("       {} scan <xmlfile> [rowpath]", args[0]);
+    fatalerr!("       {} xsd <schemafile> <rowelement>", args[0]);
+  }
+  if args[1] == "scan" { // Generate a configuration file from a sample
+    if args.len() < 3 { fatalerr!("Usage: {} scan <xmlfile> [rowpath]", args[0]); }
+    scan::scan(&args[2], args.get(3).map(String::as_str));
+    return;
+  }
+  if args[1] == "xsd" { // Generate a configuration file from an XML Schema
+    if args.len() < 4 { fatalerr!("Usage: {} xsd <schemafile> <rowelement>", args[0]); }
+    xsd::xsd(&args[2], &args[3]);
+    return;
+  }
+  if check { // Validate the configuration file only
+    std::process::exit(if check::check(&args[1]) { 0 } else { 1 });
   }
+  let inputs = match args.len() {
+    2 => vec![String::from("-")], // No input files; read from stdin
+    _ => expand_inputs(&args[2..])
+  };
 
   let config = {
     let mut config_str = String::new();
@@ -513,20 +1429,67 @@ fn main() {
     &YamlLoader::load_from_str(&config_str).unwrap_or_else(|err| fatalerr!("Error: invalid syntax in configuration file: {}", err))[0]
   };
 
-  let name = config["name"].as_str().unwrap_or_else(|| fatalerr!("Error: no valid 'name' entry in configuration file"));
-  let rowpath = config["path"].as_str().unwrap_or_else(|| fatalerr!("Error: no valid 'path' entry in configuration file"));
-  let colspec = config["cols"].as_vec().unwrap_or_else(|| fatalerr!("Error: no valid 'cols' array in configuration file"));
-  let outfile = config["file"].as_str();
+  let tabspecs: Vec<&Yaml> = match &config["tables"] { // Either a single table at the top level or a list of them
+    Yaml::BadValue => vec![config],
+    Yaml::Array(tables) => {
+      if let Some(key) = ["name", "path", "file", "cols", "skip"].iter().find(|key| !config[**key].is_badvalue()) {
+        fatalerr!("Error: the '{}' option belongs in the entries of 'tables' when that is used", key);
+      }
+      tables.iter().collect()
+    },
+    _ => fatalerr!("Error: invalid 'tables' setting in configuration file; should be a list of tables")
+  };
   let emit = config["emit"].as_str().unwrap_or("");
+  let (namespaces, ns_ignore) = match &config["namespaces"] { // Maps namespace URIs to the prefixes used in the configuration
+    Yaml::BadValue => (HashMap::new(), false),
+    Yaml::String(value) if value == "ignore" => (HashMap::new(), true),
+    Yaml::Hash(hash) => (hash.iter().map(|(prefix, uri)| match (prefix.as_str(), uri.as_str()) {
+      (Some(prefix), Some(uri)) => (uri.to_owned(), prefix.to_owned()),
+      _ => fatalerr!("Error: invalid entry in 'namespaces' setting in configuration file: {:?}", prefix)
+    }).collect(), false),
+    _ => fatalerr!("Error: invalid 'namespaces' setting in configuration file; should be a map of prefixes to URIs or 'ignore'")
+  };
   let hush = config["hush"].as_str().unwrap_or("");
-  let mut settings = Settings {
+  let format = match config["format"].as_str() {
+    None | Some("text") => Format::Text,
+    Some("csv") => Format::Csv,
+    Some("binary") => Format::Binary,
+    Some(format) => fatalerr!("Error: invalid 'format' setting in configuration file: {}", format)
+  };
+  let delimiter = match (format, config["delimiter"].as_str()) {
+    (Format::Csv, None) => b',',
+    (_, None) => b'\t',
+    (Format::Csv, Some(delim)) if delim.len() == 1 && !delim.contains(['"', '\n', '\r']) => delim.as_bytes()[0],
+    (Format::Csv, Some(delim)) => fatalerr!("Error: invalid 'delimiter' setting in configuration file: '{}'", delim),
+    (_, Some(_)) => fatalerr!("Error: the 'delimiter' setting can only be used with format csv")
+  };
+  let connection = config["connection"].as_str().map(String::from).or_else(|| env::var("XML_TO_POSTGRES_CONNECTION").ok());
+  if format == Format::Binary && !emit.is_empty() && connection.is_none() { fatalerr!("Error: the 'emit' option cannot be used with format binary"); }
+  let settings = Settings {
     filemode: config["mode"].as_str().unwrap_or("truncate").to_owned(),
-    skip: config["skip"].as_str().unwrap_or("").to_owned(),
-    emit_copyfrom: emit.contains("copy_from") || emit.contains("create_table") || emit.contains("start_trans") || emit.contains("truncate") || emit.contains("drop_table"),
-    emit_createtable: emit.contains("create_table"),
+    zipglob: config["zip"].as_str().unwrap_or("**/*.{xml,XML}").to_owned(),
+    namespaces,
+    ns_ignore,
+    format,
+    delimiter,
+    header: config["header"].as_bool().unwrap_or(false),
+    mismatch: mismatch_policy(&config["fail"]),
+    spill: match &config["spill"] {
+      Yaml::BadValue => None,
+      Yaml::Integer(n) if *n > 0 => Some(*n as usize),
+      _ => fatalerr!("Error: invalid 'spill' setting in configuration file; should be a positive number of values")
+    },
+    explain,
+    resume,
+    connection,
+    transactions: Arc::new(Mutex::new(Vec::new())),
+    encoding: config["encoding"].as_str().map(|label| Encoding::for_label(label.as_bytes()).unwrap_or_else(|| fatalerr!("Error: invalid 'encoding' setting in configuration file: {}", label))),
+    emit_copyfrom: explain || emit.contains("copy_from") || emit.contains("create_table") || emit.contains("start_trans") || emit.contains("truncate") || emit.contains("drop_table"),
+    emit_createtable: explain || emit.contains("create_table"),
     emit_starttransaction: emit.contains("start_trans"),
     emit_truncate: emit.contains("truncate"),
     emit_droptable: emit.contains("drop_table"),
+    emit_identity: emit.contains("identity"),
     hush_version: hush.contains("version"),
     hush_info: hush.contains("info"),
     hush_notice: hush.contains("notice"),
@@ -534,31 +1497,112 @@ fn main() {
     show_progress: config["prog"].as_bool().unwrap_or_else(|| std::io::stdout().is_terminal())
   };
 
-  let maintable = add_table(name, rowpath, outfile, &settings, colspec, Cardinality::Default);
-  emit_preamble(&maintable, &settings, None);
-  if !settings.skip.is_empty() {
-    if !settings.skip.starts_with('/') { settings.skip.insert(0, '/'); }
-    settings.skip.insert_str(0, &maintable.path); // Maintable path is normalized in add_table()
+  if settings.connection.is_none() && tabspecs.iter().filter(|spec| spec["file"].is_badvalue()).count() > 1 { // Their COPY statements would get mixed up
+    fatalerr!("Error: only one of the tables in 'tables' can go without a 'file' entry, as its output is written to stdout");
+  }
+  let maintables: Vec<Table> = tabspecs.iter().map(|spec| {
+    let name = spec["name"].as_str().unwrap_or_else(|| fatalerr!("Error: no valid 'name' entry in configuration file"));
+    let rowpath = spec["path"].as_str().unwrap_or_else(|| fatalerr!("Error: no valid 'path' entry in configuration file"));
+    let colspec = spec["cols"].as_vec().unwrap_or_else(|| fatalerr!("Error: no valid 'cols' array in configuration file"));
+    let mut table = add_table(name, rowpath, spec["file"].as_str(), &settings, colspec, Cardinality::Default);
+    emit_preamble(&table, &settings, None);
+    if let Some(skip) = spec["skip"].as_str() {
+      if !skip.starts_with('/') { table.skip.push('/'); }
+      table.skip.push_str(skip);
+      table.skip.insert_str(0, &table.path); // Table path is normalized in add_table()
+    }
+    table
+  }).collect();
+  if explain { // Show the table layout only
+    for table in &maintables { explain_table(table, &settings, 0); }
+    if let Some(file) = config["rejects"].as_str() { println!("Rejected rows: {}", file); }
+    return;
   }
+  let twophase = settings.connection.as_ref().is_some_and(|connection| { // Checked up front, as the commit only comes after the whole load
+    let mut count = 0;
+    checkpoint::for_each_table(&maintables, &mut |table| if table.database { count += 1; });
+    if count < 2 { return false; }
+    let mut client = Client::connect(connection, NoTls).unwrap_or_else(|err| fatalerr!("Error: failed to connect to database: {}", db_error(&err)));
+    let max = client.query_one("SHOW max_prepared_transactions", &[]).and_then(|row| row.try_get::<_, String>(0))
+      .unwrap_or_else(|err| fatalerr!("Error: failed to read max_prepared_transactions from database: {}", db_error(&err)))
+      .parse::<usize>().unwrap_or(0);
+    match max {
+      0 => {
+        warning!(settings.hush_warning, "config", "Warning: prepared transactions are disabled on the server (max_prepared_transactions is 0), so the {} tables are committed one after the other", count);
+        false
+      },
+      max if max < count => fatalerr!("Error: loading {} tables needs max_prepared_transactions of at least {} on the server, instead of {}", count, count, max),
+      _ => true
+    }
+  });
+  let checkpoint = config["checkpoint"].as_str();
+  if checkpoint.is_some() && settings.connection.is_some() { fatalerr!("Error: the 'checkpoint' option cannot be used when loading into a database"); }
+  if checkpoint.is_some() {
+    checkpoint::for_each_table(&maintables, &mut |table| {
+      if table.file.is_none() && table.cardinality != Cardinality::None { fatalerr!("Error: the 'checkpoint' option needs a 'file' for table {}, as its output cannot be resumed otherwise", table.name); }
+    });
+  }
+  let interval = match &config["interval"] {
+    Yaml::BadValue => 300,
+    Yaml::Integer(n) if *n > 0 => *n as u64,
+    _ => fatalerr!("Error: invalid 'interval' setting in configuration file; should be a positive number of seconds")
+  };
+  let statefile = config["state"].as_str();
+  if let Some(file) = statefile.filter(|_| settings.filemode == "append") {
+    match std::fs::read_to_string(file) {
+      Ok(content) => {
+        let lastids = match YamlLoader::load_from_str(&content).map(|mut docs| docs.pop()) {
+          Ok(None) => HashMap::new(),
+          Ok(Some(Yaml::Hash(hash))) => hash.iter().map(|(key, value)| match (key.as_str(), value.as_i64()) {
+            (Some(key), Some(lastid)) if lastid >= 0 => (key.to_owned(), lastid as u64),
+            _ => fatalerr!("Error: state file '{}' has an invalid entry: {:?}", file, key)
+          }).collect(),
+          _ => fatalerr!("Error: state file '{}' is invalid", file)
+        };
+        for table in &maintables { restore_serials(table, &lastids); }
+      },
+      Err(err) if err.kind() == ErrorKind::NotFound => if !settings.hush_notice { eprintln!("Notice: state file '{}' not found; serials start from their configured offset", file); },
+      Err(err) => fatalerr!("Error: failed to read state file '{}': {}", file, err)
+    }
+  }
+  let mut resume = resume.then(|| {
+    let file = checkpoint.unwrap_or_else(|| fatalerr!("Error: option --resume needs a 'checkpoint' file in the configuration"));
+    let mut resume = Resume::load(file);
+    resume.restore(&maintables, file, &settings);
+    if !settings.hush_info { eprintln!("Info: resuming from checkpoint at position {} in {}", resume.position, resume.filename); }
+    resume
+  });
+  let rejects = config["rejects"].as_str().map(|file| match settings.filemode.as_ref() {
+    _ if resume.is_some() => OpenOptions::new().append(true).open(Path::new(file)).and_then(|f| f.set_len(resume.as_ref().unwrap().rejects.unwrap_or(0)).map(|_| f))
+      .unwrap_or_else(|err| fatalerr!("Error: failed to open rejects file '{}' to resume: {}", file, err)),
+    "append" => OpenOptions::new().append(true).create(true).open(Path::new(file)).unwrap_or_else(|err| fatalerr!("Error: failed to open rejects file '{}': {}", file, err)),
+    _ => File::create(Path::new(file)).unwrap_or_else(|err| fatalerr!("Error: failed to create rejects file '{}': {}", file, err))
+  });
 
-  let mut reader;
-  reader = Reader::from_reader(bufread);
-  reader.config_mut().trim_text(true);
-  reader.config_mut().expand_empty_elements = true;
   let mut state = State {
     settings,
-    reader,
+    reader: Reader::from_reader(Box::new(std::io::empty())), // Replaced by the first input in the main loop below
+    filename: String::new(),
+    offset: 0,
+    nsbindings: Vec::new(),
+    maintables: &maintables,
     tables: Vec::new(),
-    table: &maintable,
-    rowpath: rowpath.to_string(),
+    table: &maintables[0],
     path: String::new(),
     parentcol: None,
+    forks: Vec::new(),
     deferred: None,
+    deferrals: Vec::new(),
+    replay: None,
     filtered: false,
     skipped: false,
     fullcount: 0,
     filtercount: 0,
     skipcount: 0,
+    rejectcount: 0,
+    nullcount: 0,
+    rejects,
+    quarantined: 0,
     concattext: false,
     xmltotext: false,
     text: String::new(),
@@ -570,131 +1614,377 @@ fn main() {
   };
 
   let mut buf = Vec::new();
-  let mut deferred = Vec::new();
   let mut events = 0;
   let mut report = 2;
+  let mut filecounts = Vec::new();
   let start = Instant::now();
-  'main: loop { // Main loop over the XML nodes
-    let event = state.reader.read_event_into(&mut buf).unwrap_or_else(|e| fatalerr!("Error: failed to parse XML at position {}: {}", state.reader.buffer_position(), e));
-    if state.settings.show_progress && !state.settings.hush_info {
-      events += 1;
-      if events%10000 == 0 && start.elapsed().as_secs() > report {
-        report += 2;
-        eprint!("\rInfo: [{}] {} rows processed{}{}",
-          state.tables.first().unwrap_or(&state.table).name,
-          state.fullcount-state.filtercount-state.skipcount,
-          match state.filtercount { 0 => "".to_owned(), n => format!(" ({} excluded)", n) },
-          match state.skipcount { 0 => "".to_owned(), n => format!(" ({} skipped)", n) }
-        );
-      }
+  let multiple = inputs.len() > 1;
+  let members = format!("{}:", inputs[0]); // ZIP members are named after their archive
+  let size: Option<u64> = inputs.iter().map(|input| match input.as_str() { // Total size of the input, for the progress report
+    "-" => None,
+    path => std::fs::metadata(path).ok().map(|meta| meta.len())
+  }).sum();
+  let read = Arc::new(AtomicU64::new(0));
+  let mut checkpoint = checkpoint.map(|file| Checkpoint::new(file, interval, resume.as_ref()));
+  let skip = resume.as_ref().map_or(0, |resume| resume.input);
+  let inputs = Inputs { paths: inputs.into_iter(), read: read.clone(), members: None, zipglob: state.settings.zipglob.clone(), encoding: state.settings.encoding, hush_warning: state.settings.hush_warning, skip };
+  for (index, (input, reader)) in inputs.enumerate().map(|(i, item)| (i+skip, item)) { // Loop over the input files (and ZIP members), keeping the tables and their state
+    state.reader = reader;
+    state.filename = input;
+    state.offset = 0;
+    state.path.clear();
+    state.nsbindings.clear();
+    state.step = Step::Next;
+    let mut startcount = state.fullcount-state.filtercount-state.skipcount-state.rejectcount;
+    if let Some(resume) = resume.take() { // Continue after the last row before the checkpoint
+      if state.filename != resume.filename { fatalerr!("Error: input {} is '{}' instead of '{}' as at the checkpoint", index+1, state.filename, resume.filename); }
+      if state.filename == "stdin" { fatalerr!("Error: input from stdin cannot be resumed from a checkpoint"); }
+      let mut bufread = mem::replace(&mut state.reader, Reader::from_reader(Box::new(std::io::empty()))).into_inner();
+      let skipped = std::io::copy(&mut (&mut bufread).take(resume.position), &mut std::io::sink()).unwrap_or_else(|err| fatalerr!("Error: failed to read from {}: {}", state.filename, err));
+      if skipped < resume.position { fatalerr!("Error: input '{}' is shorter than at the checkpoint", state.filename); }
+      state.reader = xml_reader(bufread);
+      state.reader.config_mut().allow_unmatched_ends = true; // The enclosing elements were opened before the checkpoint
+      state.offset = resume.position;
+      state.path = resume.path;
+      state.nsbindings = resume.nsbindings;
+      state.table = &maintables[resume.main];
+      [state.fullcount, state.filtercount, state.skipcount, state.rejectcount, state.nullcount, state.quarantined] = resume.counts;
+      filecounts = resume.filecounts;
+      startcount = resume.startcount;
     }
-    loop { // Repeat loop to be able to process a node twice
-      state.step = process_event(&event, &mut state);
-      match state.step {
-        Step::Next => break,
-        Step::Repeat => {
-            // if !deferred.is_empty() { deferred.clear(); }
-            continue
-        },
-        Step::Defer => {
-          // println!("Defer {:?}", event);
-          deferred.push(event.into_owned());
-          break;
-        },
-        Step::Apply => {
-          if state.table.lastid.borrow().is_empty() {
-            fatalerr!("Subtable defer failed to yield a key for parent table");
-          }
-          // println!("Applying {} deferred events", deferred.len());
-          state.step = Step::Repeat;
-          state.path = state.deferred.unwrap();
-          state.deferred = None;
-          deferred.reverse();
-          let mut event = deferred.pop().expect("deferred array should never be empty at this stage");
-          loop {
-            // println!("Event: {:?}", event);
-            state.step = process_event(&event, &mut state);
-            match state.step {
-              Step::Repeat => continue,
-              Step::Defer => fatalerr!("Error: you have nested subtables that need non-linear processing; this is not currently supported"),
-              Step::Done => break 'main,
-              _ => ()
-            }
-            let result = deferred.pop();
-            if result.is_none() { break; }
-            event = result.unwrap();
-          }
-          state.path.clear();
-          let i = state.table.path.rfind('/').unwrap();
-          state.path.push_str(&state.table.path[0..i]);
-          break;
-        },
-        Step::Done => break 'main
+    'main: loop { // Main loop over the XML nodes
+      let event = state.reader.read_event_into(&mut buf).unwrap_or_else(|e| fatalerr!("Error: failed to parse XML in {} at position {}: {}", state.filename, state.position(), e));
+      if state.settings.show_progress && !state.settings.hush_info {
+        events += 1;
+        if events%10000 == 0 && start.elapsed().as_secs() > report {
+          report += 2;
+          let bytes = read.load(Ordering::Relaxed);
+          let rate = bytes as f64/start.elapsed().as_secs_f64();
+          eprint!("\rInfo: [{}] {} rows processed{}{}{}; {}{} MB{} at {:.1} MB/s{}{}",
+            state.tables.first().unwrap_or(&state.table).name,
+            state.fullcount-state.filtercount-state.skipcount-state.rejectcount,
+            match state.filtercount { 0 => "".to_owned(), n => format!(" ({} excluded)", n) },
+            match state.skipcount { 0 => "".to_owned(), n => format!(" ({} skipped)", n) },
+            match state.rejectcount { 0 => "".to_owned(), n => format!(" ({} rejected)", n) },
+            bytes/1_000_000,
+            match size { Some(size) => format!(" of {}", size/1_000_000), None => "".to_owned() },
+            match size { Some(size) if size > 0 => format!(" ({}%)", (bytes*100/size).min(100)), _ => "".to_owned() },
+            rate/1_000_000.0,
+            match size { // Compressed input counts by its compressed size, so the estimate holds for it too
+              Some(size) if rate > 0.0 => {
+                let eta = (size.saturating_sub(bytes) as f64/rate) as u64;
+                format!(", ETA {}:{:02}:{:02}", eta/3600, eta/60%60, eta%60)
+              },
+              _ => "".to_owned()
+            },
+            match multiple || state.filename.starts_with(&members) { true => format!("; reading {}", state.filename), false => "".to_owned() }
+          );
+        }
+      }
+      if handle_event(&event, &mut state, 0) == Step::Done { break 'main; }
+      if let Some(checkpoint) = checkpoint.as_mut() { // Only between rows, so the parsing can resume from this position
+        if checkpoint.due() && state.tables.is_empty() && state.deferrals.is_empty() && state.forks.is_empty() && !path_match(&state.path, &state.table.path) && !path_within(&state.path, &state.table.path) {
+          checkpoint.write(&state, index, startcount, &filecounts);
+        }
       }
+      buf.clear();
     }
-    buf.clear();
+    filecounts.push((state.filename.clone(), state.fullcount-state.filtercount-state.skipcount-state.rejectcount-startcount, state.position()));
   }
-  if !state.settings.hush_warning { check_columns_used(&maintable); }
+  let mut unused = Vec::new();
+  for table in &maintables { check_columns_used(table, &state.settings, &mut unused); }
   if !state.settings.hush_info {
     let elapsed = start.elapsed().as_secs_f32();
-    eprintln!("{}Info: [{}] {} rows processed in {:.*} seconds{}{}",
+    eprintln!("{}Info: [{}] {} rows processed in {:.*} seconds{}{}{}{}{}{}",
       match state.settings.show_progress { true => "\r", false => "" },
-      maintable.name,
-      state.fullcount-state.filtercount-state.skipcount,
+      maintables.iter().map(|t| t.name.as_str()).collect::<Vec<&str>>().join(", "),
+      state.fullcount-state.filtercount-state.skipcount-state.rejectcount,
       if elapsed > 9.9 { 0 } else if elapsed > 0.99 { 1 } else if elapsed > 0.099 { 2 } else { 3 },
       elapsed,
       match state.filtercount { 0 => "".to_owned(), n => format!(" ({} excluded)", n) },
-      match state.skipcount { 0 => "".to_owned(), n => format!(" ({} skipped)", n) }
+      match state.skipcount { 0 => "".to_owned(), n => format!(" ({} skipped)", n) },
+      match state.rejectcount { 0 => "".to_owned(), n => format!(" ({} rejected)", n) },
+      match state.nullcount { 0 => "".to_owned(), n => format!(" ({} invalid values set to null)", n) },
+      match maintables.len() { 1 => "".to_owned(), _ => format!(" ({})", maintables.iter().map(|t| format!("{}: {}", t.name, t.rows.get())).collect::<Vec<String>>().join(", ")) },
+      match filecounts.len() { 0 | 1 => "".to_owned(), _ => format!(" from {} files ({})", filecounts.len(), filecounts.iter().map(|(file, rows, _)| format!("{}: {}", file, rows)).collect::<Vec<String>>().join(", ")) }
     );
   }
+  if let Some(file) = stats {
+    let json = run_stats(&maintables, &state, &filecounts, &unused, start.elapsed().as_secs_f64());
+    File::create(&file).and_then(|mut f| f.write_all(json.as_bytes())).unwrap_or_else(|err| fatalerr!("Error: failed to write stats file '{}': {}", file, err));
+  }
+  let mut lastids = String::new();
+  for table in &maintables { save_serials(table, &mut lastids); }
+  if state.quarantined > 0 && !state.settings.hush_info { eprintln!("Info: {} rejected rows written to {}", state.quarantined, config["rejects"].as_str().unwrap()); }
+  if state.settings.connection.is_some() { // Commit only when the COPY into every table has succeeded
+    let hush_info = state.settings.hush_info;
+    let transactions = state.settings.transactions.clone();
+    drop(state);
+    drop(maintables); // Finishes the COPY on all connections
+    let mut transactions = transactions.lock().unwrap();
+    commit_database(&mut transactions, twophase);
+    if !hush_info { eprintln!("Info: committed {} tables to the database", transactions.len()); }
+  }
+  if let Some(file) = statefile { // Written last, so a failed run leaves the previous state
+    std::fs::write(file, lastids).unwrap_or_else(|err| fatalerr!("Error: failed to write state file '{}': {}", file, err));
+  }
+  if let Some(checkpoint) = checkpoint { checkpoint.remove(); } // The run is complete
 }
 
-fn check_columns_used(table: &Table) {
+fn expand_inputs(args: &[String]) -> Vec<String> {
+  let mut inputs = Vec::new();
+  for arg in args {
+    let wildcard = match arg.find(['*', '?', '[', '{']) {
+      Some(i) if !Path::new(arg).exists() => i,
+      _ => { // Plain path (or a file that happens to contain glob characters)
+        inputs.push(arg.clone());
+        continue;
+      }
+    };
+    let dir = match arg[..wildcard].rfind('/') {
+      Some(i) => &arg[..=i],
+      None => ""
+    };
+    let mut files = Vec::new();
+    glob_files(dir, arg, arg[dir.len()..].contains('/'), &mut files);
+    if files.is_empty() { fatalerr!("Error: no input files match pattern '{}'", arg); }
+    files.sort();
+    inputs.append(&mut files);
+  }
+  inputs
+}
+fn glob_files(dir: &str, pattern: &str, recurse: bool, files: &mut Vec<String>) {
+  let entries = std::fs::read_dir(if dir.is_empty() { "." } else { dir }).unwrap_or_else(|err| fatalerr!("Error: failed to read directory '{}': {}", dir, err));
+  for entry in entries.flatten() {
+    let path = format!("{}{}", dir, entry.file_name().to_string_lossy());
+    if Path::new(&path).is_dir() {
+      if recurse { glob_files(&format!("{}/", path), pattern, recurse, files); }
+    }
+    else if glob_match(pattern, &path) { files.push(path); }
+  }
+}
+
+struct Inputs {
+  paths: std::vec::IntoIter<String>,
+  read: Arc<AtomicU64>, // Bytes read from the input files (before decompression)
+  members: Option<mpsc::Receiver<(String, ChannelReader)>>,
+  zipglob: String,
+  encoding: Option<&'static Encoding>,
+  hush_warning: bool,
+  skip: usize // Inputs completed before a checkpoint
+}
+impl Iterator for Inputs {
+  type Item = (String, Reader<Box<dyn BufRead>>);
+  fn next(&mut self) -> Option<Self::Item> {
+    loop {
+      if let Some(ref members) = self.members {
+        match members.recv() {
+          Ok((_, mut member)) if self.skip > 0 => { // Read through, as the archive is read in order
+            self.skip -= 1;
+            std::io::copy(&mut member, &mut std::io::sink()).unwrap_or_else(|err| fatalerr!("Error: failed to read ZIP member: {}", err));
+            continue;
+          },
+          Ok((name, member)) => {
+            let bufread = transcode(decompress(Box::new(member), &name), &name, self.encoding);
+            return Some((name, xml_reader(bufread)));
+          },
+          Err(_) => self.members = None // The unzip thread has finished
+        }
+      }
+      let path = self.paths.next()?;
+      if path == "-" {
+        if self.skip > 0 { fatalerr!("Error: input from stdin cannot be resumed from a checkpoint"); }
+        let bufread = transcode(decompress(Box::new(BufReader::new(Counter { inner: stdin(), count: self.read.clone() })), "stdin"), "stdin", self.encoding);
+        return Some((String::from("stdin"), xml_reader(bufread)));
+      }
+      let mut file = BufReader::new(Counter { inner: File::open(&path).unwrap_or_else(|err| fatalerr!("Error: failed to open input file '{}': {}", path, err)), count: self.read.clone() });
+      match file.fill_buf() {
+        Ok([b'P', b'K', 3, 4, ..]) | Ok([b'P', b'K', 5, 6, ..]) => { // ZIP archive; read its members in a separate thread
+          self.members = Some(unzip(file, path, self.zipglob.clone(), self.hush_warning));
+          continue;
+        },
+        Ok(_) if self.skip > 0 => {
+          self.skip -= 1;
+          self.read.fetch_add(std::fs::metadata(&path).map_or(0, |meta| meta.len()), Ordering::Relaxed);
+          continue;
+        },
+        Ok(_) => (),
+        Err(err) => fatalerr!("Error: failed to read from input file '{}': {}", path, err)
+      }
+      let bufread = transcode(decompress(Box::new(file), &path), &path, self.encoding);
+      return Some((path, xml_reader(bufread)));
+    }
+  }
+}
+
+fn decompress(mut bufread: Box<dyn BufRead>, input: &str) -> Box<dyn BufRead> {
+  let compression = match bufread.fill_buf() { // Detect compressed input by its magic bytes
+    Ok([0x1f, 0x8b, ..]) => Some("gzip"),
+    Ok([b'B', b'Z', b'h', ..]) => Some("bzip2"),
+    Ok([0xfd, b'7', b'z', b'X', b'Z', 0x00, ..]) => Some("xz"),
+    Ok([0x28, 0xb5, 0x2f, 0xfd, ..]) => Some("zstd"),
+    Ok([b'P', b'K', 3, 4, ..]) => fatalerr!("Error: ZIP archives can only be read from a file, not from {}", input),
+    Ok(_) => None,
+    Err(err) => fatalerr!("Error: failed to read from {}: {}", input, err)
+  };
+  match compression {
+    Some(format) => {
+      let decoder: Box<dyn Read> = match format {
+        "gzip" => Box::new(MultiGzDecoder::new(bufread)),
+        "bzip2" => Box::new(MultiBzDecoder::new(bufread)),
+        "xz" => Box::new(XzDecoder::new_multi_decoder(bufread)),
+        _ => Box::new(ZstdDecoder::with_buffer(bufread).unwrap_or_else(|err| fatalerr!("Error: failed to initialize zstd decoder for {}: {}", input, err)))
+      };
+      Box::new(BufReader::new(Decompressor { decoder, format, input: input.to_owned() }))
+    },
+    None => bufread
+  }
+}
+fn transcode(mut bufread: Box<dyn BufRead>, input: &str, encoding: Option<&'static Encoding>) -> Box<dyn BufRead> {
+  lazy_static! {
+    static ref RE: regex::bytes::Regex = regex::bytes::Regex::new(r#"^(?-u:\xEF\xBB\xBF)?<\?xml[^>]+encoding\s*=\s*["']([A-Za-z0-9._:-]+)["']"#).unwrap();
+  }
+  let encoding = match encoding { // The 'encoding' setting overrides the encoding declared in the input
+    Some(encoding) => encoding,
+    None => match bufread.fill_buf() {
+      Ok([0xff, 0xfe, ..]) => UTF_16LE,
+      Ok([0xfe, 0xff, ..]) => UTF_16BE,
+      Ok(prolog) => match RE.captures(prolog) {
+        Some(caps) => Encoding::for_label(&caps[1]).unwrap_or_else(|| fatalerr!("Error: unsupported encoding '{}' declared in {}", String::from_utf8_lossy(&caps[1]), input)),
+        None => UTF_8
+      },
+      Err(err) => fatalerr!("Error: failed to read from {}: {}", input, err)
+    }
+  };
+  if encoding == UTF_8 { return bufread; }
+  // Transcode to UTF-8 up front so that all values (and thus the output) are valid UTF-8
+  Box::new(BufReader::new(DecodeReaderBytesBuilder::new().encoding(Some(encoding)).build(bufread)))
+}
+fn xml_reader(bufread: Box<dyn BufRead>) -> Reader<Box<dyn BufRead>> {
+  let mut reader = Reader::from_reader(bufread);
+  reader.config_mut().trim_text(true);
+  reader.config_mut().expand_empty_elements = true;
+  reader
+}
+
+fn unzip(file: BufReader<Counter<File>>, path: String, zipglob: String, hush_warning: bool) -> mpsc::Receiver<(String, ChannelReader)> {
+  let (tx, rx) = mpsc::sync_channel(0);
+  thread::Builder::new().name(format!("unzip {}", path)).spawn(move || {
+    let mut archive = ZipArchive::new(file).unwrap_or_else(|err| fatalerr!("Error: failed to open ZIP archive '{}': {}", path, err));
+    if unzip_members(&mut archive, &path, &zipglob, &tx) == 0 {
+      warning!(hush_warning, "zip", "Warning: ZIP archive '{}' contains no members matching '{}'", path, zipglob);
+    }
+  }).unwrap_or_else(|err| fatalerr!("Error: failed to create unzip thread: {}", err));
+  rx
+}
+fn unzip_members<R: Read + Seek>(archive: &mut ZipArchive<R>, path: &str, zipglob: &str, tx: &mpsc::SyncSender<(String, ChannelReader)>) -> usize {
+  let mut count = 0;
+  for i in 0..archive.len() {
+    let mut member = archive.by_index(i).unwrap_or_else(|err| fatalerr!("Error: failed to read member {} of ZIP archive '{}': {}", i, path, err));
+    if member.is_dir() { continue; }
+    let name = format!("{}:{}", path, member.name());
+    if member.name().to_lowercase().ends_with(".zip") { // Nested archive; needs to be read into memory for random access
+      let mut data = Vec::with_capacity(member.size() as usize);
+      member.read_to_end(&mut data).unwrap_or_else(|err| fatalerr!("Error: failed to decompress ZIP member '{}': {}", name, err));
+      let mut nested = ZipArchive::new(Cursor::new(data)).unwrap_or_else(|err| fatalerr!("Error: failed to open ZIP archive '{}': {}", name, err));
+      count += unzip_members(&mut nested, &name, zipglob, tx);
+      continue;
+    }
+    if !glob_match(zipglob, member.name()) { continue; }
+    count += 1;
+    let (datatx, datarx) = mpsc::sync_channel(4);
+    if tx.send((name.clone(), ChannelReader { rx: datarx, chunk: Vec::new(), pos: 0 })).is_err() { return count; }
+    loop {
+      let mut chunk = vec![0; 65536];
+      let len = member.read(&mut chunk).unwrap_or_else(|err| fatalerr!("Error: failed to decompress ZIP member '{}': {}", name, err));
+      if len == 0 { break; }
+      chunk.truncate(len);
+      if datatx.send(chunk).is_err() { return count; }
+    }
+  }
+  count
+}
+
+fn check_columns_used(table: &Table, settings: &Settings, unused: &mut Vec<String>) {
   for col in &table.columns {
-    if col.subtable.is_some() {
-      let sub = col.subtable.as_ref().unwrap();
-      check_columns_used(sub);
+    if let Some(sub) = &col.subtable {
+      check_columns_used(sub, settings, unused);
     }
     else if !*col.used.borrow() {
-      eprintln!("Warning: table {} column {} was never found", table.name, col.name);
+      warning!(settings.hush_warning, "unused", "Warning: table {} column {} was never found", table.name, col.name);
+      unused.push(format!("{}.{}", table.name, col.name));
     }
   }
 }
 
+fn restore_serials(table: &Table, lastids: &HashMap<String, u64>) { // Continues the 'seri' numbering from the state file
+  if let Some((column, _)) = table.last_serial() {
+    if let Some(lastid) = lastids.get(&format!("{}.{}", table.name, column)) {
+      let serial = table.columns.iter().find(|c| c.name == column).unwrap().serial.as_ref().unwrap();
+      serial.set(serial.get().max(*lastid));
+      if let Some(domain) = table.domain.as_ref() {
+        let mut domain = domain.borrow_mut();
+        domain.lastid = domain.lastid.max(*lastid);
+      }
+    }
+  }
+  for col in &table.columns {
+    if let Some(sub) = &col.subtable { restore_serials(sub, lastids); }
+  }
+}
+fn save_serials(table: &Table, state: &mut String) {
+  if let Some((column, lastid)) = table.last_serial() { writeln!(state, "{}.{}: {}", table.name, column, lastid).unwrap(); }
+  for col in &table.columns {
+    if let Some(sub) = &col.subtable { save_serials(sub, state); }
+  }
+}
+
 fn process_event(event: &Event, state: &mut State) -> Step {
   let table = &state.table;
   match event {
-    Event::Decl(ref e) => {
-      if !state.settings.hush_version && !state.settings.hush_info {
-        eprintln!("Info: reading XML version {} with encoding {}",
-          str::from_utf8(&e.version().unwrap_or_else(|_| fatalerr!("Error: missing or invalid XML version attribute: {:#?}", e.as_ref()))).unwrap(),
-          str::from_utf8(match e.encoding() {
-            Some(Ok(Cow::Borrowed(encoding))) => encoding,
-            _ => b"unknown"
-          }).unwrap()
-        );
-      }
+    Event::Decl(ref e) if !state.settings.hush_version && !state.settings.hush_info => {
+      eprintln!("Info: reading XML version {} with encoding {}",
+        str::from_utf8(&e.version().unwrap_or_else(|_| fatalerr!("Error: missing or invalid XML version attribute: {:#?}", e.as_ref()))).unwrap(),
+        str::from_utf8(match e.encoding() {
+          Some(Ok(Cow::Borrowed(encoding))) => encoding,
+          _ => b"unknown"
+        }).unwrap()
+      );
     },
     Event::Start(ref e) => {
+      if !state.settings.namespaces.is_empty() {
+        let depth = state.path.matches('/').count() + if state.step == Step::Repeat { 0 } else { 1 };
+        bind_namespaces(e, depth, state.reader.decoder(), &mut state.nsbindings);
+      }
       if state.step != Step::Repeat {
+        let qname = e.name();
+        let tag = state.reader.decoder().decode(qname.as_ref()).unwrap_or_else(|err| fatalerr!("Error: failed to decode XML tag '{}': {}", String::from_utf8_lossy(qname.as_ref()), err));
+        let name = resolve_name(state, &tag, false);
         state.path.push('/');
-        state.path.push_str(&state.reader.decoder().decode(e.name().as_ref()).unwrap_or_else(|err| fatalerr!("Error: failed to decode XML tag '{}': {}", String::from_utf8_lossy(e.name().as_ref()), err)));
+        state.path.push_str(&name);
       }
-      if let Some(path) = &state.deferred {
-        if state.path.starts_with(path) { return Step::Defer; }
+      if state.deferred.as_ref().is_some_and(|path| state.path == *path || path_within(&state.path, path)) {
+        return defer_event(state, event);
       }
       if state.filtered || state.skipped { return Step::Next; }
+      if state.tables.is_empty() && state.maintables.len() > 1 && !path_match(&state.path, &table.path) && !path_within(&state.path, &table.path) {
+        if let Some(main) = state.maintables.iter().find(|t| path_match(&state.path, &t.path)) { // Start of a row of another main table
+          state.table = main;
+          return Step::Repeat;
+        }
+      }
       if !state.tables.is_empty() && path_match(&state.path, &table.path) { // Start of a subtable
-        if table.cardinality != Cardinality::ManyToOne { // Subtable needs a foreign key from parent
-          if state.tables.last().unwrap().lastid.borrow().is_empty() {
-            if state.deferred.is_some() { fatalerr!("Error: you have multiple subtables that precede the parent table id column; this is not currently supported"); }
-            // println!("Defer subtable {}", table.name);
-            state.deferred = Some(state.path.clone());
-            return Step::Defer;
+        let parent = *state.tables.last().unwrap();
+        // Subtable needs a foreign key from parent; when replaying, the parent had no key at all and the row is rejected at its end
+        if table.cardinality != Cardinality::ManyToOne && parent.lastid.borrow().is_empty() && !state.replay.is_some_and(|t| ptr::eq(t, parent)) {
+          if !state.deferrals.last().is_some_and(|d| ptr::eq(d.table, parent)) { // Sibling subtables share the deferral of their parent row
+            state.deferrals.push(Deferral { table: parent, events: Vec::new() });
           }
+          state.deferred = Some(state.path.clone());
+          return defer_event(state, event);
         }
       }
-      if path_match(&state.path, &state.settings.skip) {
+      if path_match(&state.path, &state.tables.first().unwrap_or(&state.table).skip) {
         state.skipped = true;
         return Step::Next;
       }
@@ -708,24 +1998,24 @@ fn process_event(event: &Event, state: &mut State) -> Step {
       else if state.gmltoewkb {
         match state.reader.decoder().decode(e.name().as_ref()) {
           Err(_) => (),
-          Ok(tag) => match tag.as_ref() {
-            "gml:Point" => {
+          Ok(tag) => match gml_name(state, &tag) {
+            Some("Point") => {
               state.gmlcoll.push(Geometry::new(1));
               state.gmlcoll.last_mut().unwrap().rings.push(Vec::new());
             },
-            "gml:LineString" => {
+            Some("LineString") => {
               state.gmlcoll.push(Geometry::new(2));
               state.gmlcoll.last_mut().unwrap().rings.push(Vec::new());
             },
-            "gml:Polygon" => state.gmlcoll.push(Geometry::new(3)),
-            "gml:MultiPolygon" => (),
-            "gml:polygonMember" => (),
-            "gml:exterior" => (),
-            "gml:interior" => (),
-            "gml:LinearRing" => state.gmlcoll.last_mut().unwrap().rings.push(Vec::new()),
-            "gml:posList" => state.gmlpos = true,
-            "gml:pos" => state.gmlpos = true,
-            _ => if !state.settings.hush_warning { eprintln!("Warning: GML type {} not supported", tag); }
+            Some("Polygon") => state.gmlcoll.push(Geometry::new(3)),
+            Some("MultiPolygon") => (),
+            Some("polygonMember") => (),
+            Some("exterior") => (),
+            Some("interior") => (),
+            Some("LinearRing") => state.gmlcoll.last_mut().unwrap().rings.push(Vec::new()),
+            Some("posList") => state.gmlpos = true,
+            Some("pos") => state.gmlpos = true,
+            _ => warning!(state.settings.hush_warning, "gml", "Warning: GML type {} not supported", tag)
           }
         }
         for res in e.attributes() {
@@ -744,7 +2034,7 @@ fn process_event(event: &Event, state: &mut State) -> Step {
                     Ok(int) => {
                       if let Some(geom) = state.gmlcoll.last_mut() { geom.srid = int };
                     },
-                    Err(_) => if !state.settings.hush_warning { eprintln!("Warning: invalid srsName {} in GML", value); }
+                    Err(_) => warning!(state.settings.hush_warning, "gml", "Warning: invalid srsName {} in GML", value)
                   }
                 },
                 "srsDimension" => {
@@ -753,7 +2043,7 @@ fn process_event(event: &Event, state: &mut State) -> Step {
                     Ok(int) => {
                       if let Some(geom) = state.gmlcoll.last_mut() { geom.dims = int };
                     },
-                    Err(_) => if !state.settings.hush_warning { eprintln!("Warning: invalid srsDimension {} in GML", value); }
+                    Err(_) => warning!(state.settings.hush_warning, "gml", "Warning: invalid srsDimension {} in GML", value)
                   }
                 }
                 _ => ()
@@ -764,20 +2054,23 @@ fn process_event(event: &Event, state: &mut State) -> Step {
         return Step::Next;
       }
 
-      if path_match(&state.path, &table.path) { state.table.lastid.borrow_mut().clear(); }
-      if path_match(&state.path, &state.rowpath) { state.fullcount += 1; }
-      let mut subtable = None;
+      if path_match(&state.path, &table.path) {
+        state.table.lastid.borrow_mut().clear();
+        table.hold_rows();
+      }
+      if state.tables.is_empty() && path_match(&state.path, &table.path) { state.fullcount += 1; }
+      let mut subtables = Vec::new();
 
       for i in 0..table.columns.len() {
         if path_match(&state.path, &table.columns[i].path) { // This start tag matches one of the defined columns
           // Handle the 'seri' case where this column is a virtual auto-incrementing serial
           if let Some(ref serial) = table.columns[i].serial {
             // if table.cardinality == Cardinality::ManyToOne { continue; }
+            if table.columns[i].hash.is_some() { continue; } // Set when its columns have their values
             if table.columns[i].value.borrow().is_empty() {
               let id = serial.get()+1;
-              let idstr = id.to_string();
-              table.columns[i].value.borrow_mut().push_str(&idstr);
-              table.lastid.borrow_mut().push_str(&idstr);
+              table.columns[i].value.borrow_mut().push_str(&id.to_string());
+              if table.key.contains(&i) { table.update_key(); }
               serial.set(id);
               continue;
             }
@@ -800,35 +2093,38 @@ fn process_event(event: &Event, state: &mut State) -> Step {
           }
           // Handle 'subtable' case (the 'cols' entry has 'cols' of its own)
           if table.columns[i].subtable.is_some() {
-              if subtable.is_some() { fatalerr!("Error: multiple subtables starting from the same element is not supported"); }
-              subtable = Some(i);
+              subtables.push(i);
           }
           // Handle the 'attr' case where the content is read from an attribute of this tag
           if let Some(request) = table.columns[i].attr {
             for res in e.attributes() {
               if let Ok(attr) = res {
                 if let Ok(key) = state.reader.decoder().decode(attr.key.as_ref()) {
-                  if key == request {
+                  if resolve_name(state, &key, true) == request {
                     if let Ok(value) = state.reader.decoder().decode(&attr.value) {
                       if !table.columns[i].value.borrow().is_empty() {
                         if !allow_iteration(&table.columns[i], &state.settings) { break; }
                         if let Some("last") = table.columns[i].aggr { table.columns[i].value.borrow_mut().clear(); }
                       }
-                      if i == 0 { table.lastid.borrow_mut().push_str(&value); }
                       if let (Some(regex), Some(replacer)) = (table.columns[i].find.as_ref(), table.columns[i].replace) {
                         table.columns[i].value.borrow_mut().push_str(&regex.replace_all(&value, replacer));
                       }
                       else { table.columns[i].value.borrow_mut().push_str(&value); }
+                      if table.key.contains(&i) {
+                        let position = state.position();
+                        table.check_key(i, &mut state.nullcount, &state.filename, position);
+                      }
+                      table.update_hashes(Some(i));
                     }
-                    else if !state.settings.hush_warning { eprintln!("Warning: failed to decode attribute {} for column {}", request, table.columns[i].name); }
+                    else { warning!(state.settings.hush_warning, "attribute", "Warning: failed to decode attribute {} for column {}", request, table.columns[i].name); }
                   }
                 }
-                else if !state.settings.hush_warning { eprintln!("Warning: failed to decode an attribute for column {}", table.columns[i].name); }
+                else { warning!(state.settings.hush_warning, "attribute", "Warning: failed to decode an attribute for column {}", table.columns[i].name); }
               }
-              else if !state.settings.hush_warning { eprintln!("Warning: failed to read attributes for column {}", table.columns[i].name); }
+              else { warning!(state.settings.hush_warning, "attribute", "Warning: failed to read attributes for column {}", table.columns[i].name); }
             }
-            if table.columns[i].value.borrow().is_empty() && !state.settings.hush_warning {
-              eprintln!("Warning: column {} requested attribute {} not found", table.columns[i].name, request);
+            if table.columns[i].value.borrow().is_empty() {
+              warning!(state.settings.hush_warning, "attribute", "Warning: column {} requested attribute {} not found", table.columns[i].name, request);
             }
             continue;
           }
@@ -842,16 +2138,20 @@ fn process_event(event: &Event, state: &mut State) -> Step {
           }
         }
       }
-      if let Some(i) = subtable {
+      if !subtables.is_empty() {
+          // One that needs the key of this table goes first, so the others get deferred along with it when that key comes later
+          let first = subtables.iter().position(|i| table.columns[*i].subtable.as_ref().unwrap().cardinality != Cardinality::ManyToOne).unwrap_or(0);
+          let i = subtables.remove(first);
           state.tables.push(table);
+          for j in subtables { state.forks.push(Fork::new(state.tables.clone(), &table.columns[j])); }
           state.parentcol = Some(&table.columns[i]);
           state.table = table.columns[i].subtable.as_ref().unwrap();
           return Step::Repeat; // Continue the repeat loop because a subtable column may also match the current path
       }
     },
     Event::Text(ref e) => {
-      if let Some(path) = &state.deferred {
-        if state.path.starts_with(path) { return Step::Defer; }
+      if state.deferred.as_ref().is_some_and(|path| state.path == *path || path_within(&state.path, path)) {
+        return defer_event(state, event);
       }
       if state.filtered || state.skipped { return Step::Next; }
       if state.concattext {
@@ -880,7 +2180,11 @@ fn process_event(event: &Event, state: &mut State) -> Step {
             if let Some("last") = table.columns[i].aggr { table.columns[i].value.borrow_mut().clear(); }
           }
           let decoded = e.unescape().unwrap_or_else(|err| fatalerr!("Error: failed to decode XML text node '{}': {}", String::from_utf8_lossy(e), err));
-          if table.columns[i].trim {
+          if state.settings.format != Format::Text { // Values are quoted or encoded (where needed) when the row is written
+            if table.columns[i].trim { table.columns[i].value.borrow_mut().push_str(&state.trimre.replace_all(&decoded, " ")); }
+            else { table.columns[i].value.borrow_mut().push_str(&decoded); }
+          }
+          else if table.columns[i].trim {
             let trimmed = state.trimre.replace_all(&decoded, " ");
             table.columns[i].value.borrow_mut().push_str(&trimmed.cow_replace("\\", "\\\\").cow_replace("\t", "\\t"));
           }
@@ -892,23 +2196,33 @@ fn process_event(event: &Event, state: &mut State) -> Step {
             *value = regex.replace_all(&value, replacer).to_string();
           }
           // println!("Table {} column {} value {}", table.name, table.columns[i].name, &table.columns[i].value.borrow());
-          if i == 0 {
-              table.lastid.borrow_mut().push_str(&table.columns[0].value.borrow());
+          if table.key.contains(&i) {
+            let position = state.position();
+            table.check_key(i, &mut state.nullcount, &state.filename, position);
           }
+          table.update_hashes(Some(i));
           return Step::Next;
         }
       }
     },
     Event::End(_) => {
-      if let Some(path) = &state.deferred {
-        if state.path.starts_with(path) {
-          if path_match(&state.path, &table.path) && !state.tables.is_empty() {
-            state.table = state.tables.pop().unwrap();
-          }
-          let i = state.path.rfind('/').unwrap();
-          state.path.truncate(i);
-          return Step::Defer;
-        }
+      if state.deferred.as_ref().is_some_and(|path| path_within(&state.path, path)) {
+        defer_event(state, event);
+        let i = state.path.rfind('/').unwrap();
+        state.path.truncate(i);
+        return Step::Defer;
+      }
+      if state.deferred.as_ref() == Some(&state.path) { // End of the deferred subtable element
+        defer_event(state, event);
+        state.deferred = None;
+        state.table = state.tables.pop().unwrap();
+        let i = state.path.rfind('/').unwrap();
+        state.path.truncate(i);
+        return Step::Defer;
+      }
+      if path_match(&state.path, &table.path) { table.update_hashes(None); }
+      if path_match(&state.path, &table.path) && state.deferrals.last().is_some_and(|d| ptr::eq(d.table, *table)) { // The key of this row is known now (or never will be)
+        return Step::Apply;
       }
 
       if state.concattext {
@@ -922,36 +2236,72 @@ fn process_event(event: &Event, state: &mut State) -> Step {
       }
 
       if path_match(&state.path, &table.path) { // This is an end tag of the row path
+        let mut rejected = table.badkey.take().and_then(|(mismatch, reason)| (mismatch == Mismatch::Skip).then_some(reason));
         for i in 0..table.columns.len() {
           if !*table.columns[i].used.borrow() && !table.columns[i].value.borrow().is_empty() {
               *state.table.columns[i].used.borrow_mut() = true;
           }
           if let Some(re) = &table.columns[i].include {
             if !re.is_match(&table.columns[i].value.borrow()) {
-              state.filtered = true;
+              if table.columns[i].strict { rejected.get_or_insert_with(|| format!("column {} does not match the incl filter", table.columns[i].name)); }
+              else {
+                state.filtered = true;
+                table.columns[i].filtered.set(table.columns[i].filtered.get()+1);
+              }
             }
           }
           if let Some(re) = &table.columns[i].exclude {
             if re.is_match(&table.columns[i].value.borrow()) {
-              state.filtered = true;
+              if table.columns[i].strict { rejected.get_or_insert_with(|| format!("column {} matches the excl filter", table.columns[i].name)); }
+              else {
+                state.filtered = true;
+                table.columns[i].filtered.set(table.columns[i].filtered.get()+1);
+              }
             }
           }
         }
-        if state.filtered {
-          state.filtered = false;
+        if state.rejects.is_some() && rejected.is_none() && !state.tables.is_empty() && table.cardinality != Cardinality::ManyToOne { // Subtable rows without a usable key go to the rejects file
+          let parent = state.tables.last().unwrap();
+          if parent.lastid.borrow().is_empty() { rejected = Some(format!("no key from parent table {}", parent.name)); }
+          else if table.domain.is_some() && table.columns.len() > 1 && table.lastid.borrow().is_empty() { rejected = Some(String::from("no primary key to normalize on")); }
+        }
+        for (_, col) in table.columns.iter().enumerate().filter(|(i, _)| !state.filtered && rejected.is_none() && !table.key.contains(i)) { // Key columns are checked already
+          match col.check() {
+            Ok(()) => (),
+            Err((Mismatch::Error, reason)) => fatalerr!("Error: table {} {} (in {} at position {})", table.name, reason, state.filename, state.position()),
+            Err((Mismatch::Null, _)) => state.nullcount += 1,
+            Err((Mismatch::Skip, reason)) => {
+              rejected = Some(reason);
+              break;
+            }
+          }
+        }
+        if state.filtered || rejected.is_some() {
+          table.release_rows(false);
+          if let (Some(rejects), Some(reason), false) = (&state.rejects, &rejected, state.filtered) {
+            write_reject(rejects, table, reason, &state.filename, state.position(), &state.settings);
+            state.quarantined += 1;
+          }
           table.clear_columns();
-          if state.tables.is_empty() { state.filtercount += 1; } // Only count filtered for the main table
+          if state.tables.is_empty() { // Only count filtered for the main table
+            if state.filtered { state.filtercount += 1; }
+            else { state.rejectcount += 1; }
+            state.filtered = false;
+          }
           else { // Subtable; nothing more to do in this case
+            state.filtered = false;
             state.table = state.tables.pop().unwrap();
             return Step::Repeat;
           }
         }
         else {
+          table.release_rows(true);
+          let mut row = Row::new(&table.buf.borrow());
           if !state.tables.is_empty() { // This is a subtable
-            if table.cardinality != Cardinality::ManyToOne { // Write the first column value of the parent table as the first column of the subtable (for use as a foreign key)
-              let key = state.tables.last().unwrap().lastid.borrow();
-              if key.is_empty() && !state.settings.hush_warning { println!("Warning: subtable {} has no foreign key for parent (you may need to add a 'seri' column)", table.name); }
-              write!(table.buf.borrow_mut(), "{}\t", key).unwrap();
+            if table.cardinality != Cardinality::ManyToOne { // Write the key of the parent table as the first column(s) of the subtable (for use as a foreign key)
+              let parent = state.tables.last().unwrap();
+              if parent.lastid.borrow().is_empty() { warning!(state.settings.hush_warning, "key", "Warning: subtable {} has no foreign key for parent (you may need to add a 'seri' column)", table.name); }
+              parent.write_key(&mut row, &mut table.buf.borrow_mut(), &state.settings);
               let rowid;
               if let Some(domain) = table.domain.as_ref() {
                 let mut domain = domain.borrow_mut();
@@ -959,50 +2309,52 @@ fn process_event(event: &Event, state: &mut State) -> Step {
                     Some(_) => table.columns[1..].iter().map(|c| c.value.borrow().to_string()).collect::<String>(),
                     None => table.lastid.borrow().to_string()
                 };
-                if !domain.map.contains_key(&key) {
-                  domain.lastid += 1;
-                  rowid = domain.lastid;
+                if let Some(id) = domain.map.get(&key) {
+                  rowid = id;
+                  table.set_serial(rowid);
+                }
+                else {
+                  rowid = domain.next_id(&key);
                   domain.map.insert(key, rowid);
+                  table.set_serial(rowid);
+                  let mut domrow = Row::new(&domain.table.buf.borrow());
                   if table.columns.len() == 1 {
-                    write!(domain.table.buf.borrow_mut(), "{}\t", rowid).unwrap();
+                    domrow.field(&mut domain.table.buf.borrow_mut(), &rowid.to_string(), domain.table.columns.first(), &state.settings);
                   }
                   for i in 0..table.columns.len() {
                     if table.columns[i].subtable.is_some() { continue; }
                     if table.columns[i].hide { continue; }
-                    if i > 0 { write!(domain.table.buf.borrow_mut(), "\t").unwrap(); }
-                    if table.columns[i].value.borrow().is_empty() { write!(domain.table.buf.borrow_mut(), "\\N").unwrap(); }
+                    if table.columns[i].value.borrow().is_empty() { domrow.field(&mut domain.table.buf.borrow_mut(), "", None, &state.settings); }
                     else if let Some(domain) = table.columns[i].domain.as_ref() {
                       let mut domain = domain.borrow_mut();
                       let id = match domain.map.get(&table.columns[i].value.borrow().to_string()) {
-                        Some(id) => *id,
+                        Some(id) => id,
                         None => {
-                          domain.lastid += 1;
-                          let id = domain.lastid;
+                          let id = domain.next_id(&table.columns[i].value.borrow());
                           domain.map.insert(table.columns[i].value.borrow().to_string(), id);
-                          write!(domain.table.buf.borrow_mut(), "{}\t{}\n", id, *table.columns[i].value.borrow()).unwrap();
-                          domain.table.flush();
+                          let mut valrow = Row::new(&domain.table.buf.borrow());
+                          valrow.field(&mut domain.table.buf.borrow_mut(), &id.to_string(), domain.table.columns.first(), &state.settings);
+                          valrow.field(&mut domain.table.buf.borrow_mut(), &table.columns[i].value.borrow(), domain.table.columns.get(1), &state.settings);
+                          domain.table.end_row(valrow, &state.settings);
                           id
                         }
                       };
-                      write!(domain.table.buf.borrow_mut(), "{}", id).unwrap();
+                      domrow.field(&mut domain.table.buf.borrow_mut(), &id.to_string(), Some(&table.columns[i]), &state.settings);
                     }
                     else {
-                      write!(domain.table.buf.borrow_mut(), "{}", &table.columns[i].value.borrow()).unwrap();
+                      domrow.field(&mut domain.table.buf.borrow_mut(), &table.columns[i].value.borrow(), domain.table.columns.get(domrow.fields as usize), &state.settings);
                     }
                   }
-                  write!(domain.table.buf.borrow_mut(), "\n").unwrap();
-                  domain.table.flush();
+                  domain.table.end_row(domrow, &state.settings);
                 }
-                else { rowid = *domain.map.get(&key).unwrap(); }
                 if table.columns.len() == 1 { // Single column many-to-many subtable; needs the id from the domain map
-                  write!(table.buf.borrow_mut(), "{}" , rowid).unwrap();
+                  row.field(&mut table.buf.borrow_mut(), &rowid.to_string(), table.columns.first(), &state.settings);
                 }
                 else {
-                  if table.lastid.borrow().is_empty() && !state.settings.hush_warning { println!("Warning: subtable {} has no primary key to normalize on", table.name); }
-                  write!(table.buf.borrow_mut(), "{}" , table.lastid.borrow()).unwrap(); // This is a many-to-many relation; write the two keys into the link table
+                  if table.lastid.borrow().is_empty() { warning!(state.settings.hush_warning, "key", "Warning: subtable {} has no primary key to normalize on", table.name); }
+                  table.write_key(&mut row, &mut table.buf.borrow_mut(), &state.settings); // This is a many-to-many relation; write the two keys into the link table
                 }
-                write!(table.buf.borrow_mut(), "\n").unwrap();
-                table.flush();
+                table.end_row(row, &state.settings);
                 table.clear_columns();
                 state.table = state.tables.pop().unwrap();
                 return Step::Repeat;
@@ -1015,18 +2367,19 @@ fn process_event(event: &Event, state: &mut State) -> Step {
                     Some(_) => table.columns[1..].iter().map(|c| c.value.borrow().to_string()).collect::<String>(),
                     None => table.lastid.borrow().to_string()
                 };
-                if domain.map.contains_key(&key) {
-                  if table.columns[0].serial.is_some() {
-                    state.parentcol.unwrap().value.borrow_mut().push_str(&format!("{}", *domain.map.get(&key).unwrap()));
+                if let Some(id) = domain.map.get(&key) {
+                  if table.columns[0].hash.is_some() { state.parentcol.unwrap().value.borrow_mut().push_str(&table.columns[0].value.borrow()); }
+                  else if table.columns[0].serial.is_some() {
+                    state.parentcol.unwrap().value.borrow_mut().push_str(&id.to_string());
                   }
                   else { state.parentcol.unwrap().value.borrow_mut().push_str(&table.lastid.borrow()); }
                   table.clear_columns();
                   state.table = state.tables.pop().unwrap();
                   return Step::Repeat;
                 }
-                domain.lastid += 1;
-                let id = domain.lastid;
+                let id = domain.next_id(&key);
                 domain.map.insert(key, id);
+                table.set_serial(id);
                 // The for loop below will now write out the new row
               }
               if state.parentcol.unwrap().value.borrow().is_empty() {
@@ -1044,48 +2397,41 @@ fn process_event(event: &Event, state: &mut State) -> Step {
               table.columns[i].value.borrow_mut().clear();
               continue;
             }
-            if i > 0 { write!(table.buf.borrow_mut(), "\t").unwrap(); }
-            if table.columns[i].value.borrow().is_empty() { write!(table.buf.borrow_mut(), "\\N").unwrap(); }
+            if table.columns[i].value.borrow().is_empty() { row.field(&mut table.buf.borrow_mut(), "", Some(&table.columns[i]), &state.settings); }
             else if let Some(domain) = table.columns[i].domain.as_ref() {
               let mut domain = domain.borrow_mut();
               let id = match domain.map.get(&table.columns[i].value.borrow().to_string()) {
-                Some(id) => *id,
+                Some(id) => id,
                 None => {
-                  domain.lastid += 1;
-                  let id = domain.lastid;
+                  let id = domain.next_id(&table.columns[i].value.borrow());
                   domain.map.insert(table.columns[i].value.borrow().to_string(), id);
-                  write!(domain.table.buf.borrow_mut(), "{}\t{}\n", id, *table.columns[i].value.borrow()).unwrap();
-                  domain.table.flush();
+                  let mut valrow = Row::new(&domain.table.buf.borrow());
+                  valrow.field(&mut domain.table.buf.borrow_mut(), &id.to_string(), domain.table.columns.first(), &state.settings);
+                  valrow.field(&mut domain.table.buf.borrow_mut(), &table.columns[i].value.borrow(), domain.table.columns.get(1), &state.settings);
+                  domain.table.end_row(valrow, &state.settings);
                   id
                 }
               };
-              write!(table.buf.borrow_mut(), "{}", id).unwrap();
+              row.field(&mut table.buf.borrow_mut(), &id.to_string(), Some(&table.columns[i]), &state.settings);
               table.columns[i].value.borrow_mut().clear();
             }
             else {
-              write!(table.buf.borrow_mut(), "{}", &table.columns[i].value.borrow()).unwrap();
+              row.field(&mut table.buf.borrow_mut(), &table.columns[i].value.borrow(), Some(&table.columns[i]), &state.settings);
               table.columns[i].value.borrow_mut().clear();
             }
           }
-          write!(table.buf.borrow_mut(), "\n").unwrap();
-          table.flush();
+          table.end_row(row, &state.settings);
         }
         if !state.tables.is_empty() {
             state.table = state.tables.pop().unwrap();
             return Step::Repeat;
         }
       }
-      else if state.skipped && path_match(&state.path, &state.settings.skip) {
+      else if state.skipped && path_match(&state.path, &state.tables.first().unwrap_or(&state.table).skip) {
         state.skipped = false;
         state.skipcount += 1;
       }
 
-      if let Some(path) = &state.deferred {
-        if path_match(&state.path, &table.path) && state.path.len() < path.len() { // We've just processed the deferred subtable's parent; apply the deferred events
-          return Step::Apply;
-        }
-      }
-
       let i = state.path.rfind('/').expect("no slash in path; shouldn't happen");
       let tag = state.path.split_off(i);
 
@@ -1104,7 +2450,7 @@ fn process_event(event: &Event, state: &mut State) -> Step {
         }
       }
       else if state.gmltoewkb {
-        if state.gmlpos && ((tag == "/gml:pos") || (tag == "/gml:posList")) { state.gmlpos = false; }
+        if state.gmlpos && matches!(tag.rsplit([':', '/']).next(), Some("pos" | "posList")) { state.gmlpos = false; }
         for i in 0..table.columns.len() {
           if path_match(&state.path, &table.columns[i].path) {
             state.gmltoewkb = false;
@@ -1124,16 +2470,223 @@ fn process_event(event: &Event, state: &mut State) -> Step {
   Step::Next
 }
 
+fn run_stats(maintables: &[Table], state: &State, files: &[(String, u64, u64)], unused: &[String], elapsed: f64) -> String {
+  let mut tables = Vec::new();
+  let mut filtered = Vec::new();
+  for table in maintables { table_stats(table, &mut tables, &mut filtered); }
+  let list = |items: Vec<String>| if items.is_empty() { String::from("[]") } else { format!("[\n    {}\n  ]", items.join(",\n    ")) };
+  let map = |items: Vec<String>| if items.is_empty() { String::from("{}") } else { format!("{{\n    {}\n  }}", items.join(",\n    ")) };
+  let mut json = String::from("{\n");
+  writeln!(json, "  \"rows\": {},", state.fullcount-state.filtercount-state.skipcount-state.rejectcount).unwrap();
+  writeln!(json, "  \"excluded\": {},", state.filtercount).unwrap();
+  writeln!(json, "  \"skipped\": {},", state.skipcount).unwrap();
+  writeln!(json, "  \"rejected\": {},", state.rejectcount).unwrap();
+  writeln!(json, "  \"nulled\": {},", state.nullcount).unwrap();
+  writeln!(json, "  \"tables\": {},", list(tables)).unwrap();
+  writeln!(json, "  \"filtered\": {},", map(filtered)).unwrap();
+  writeln!(json, "  \"unused\": {},", list(unused.iter().map(|col| json_string(col)).collect())).unwrap();
+  writeln!(json, "  \"warnings\": {},", map(WARNINGS.lock().unwrap().iter().map(|(category, count)| format!("{}: {}", json_string(category), count)).collect())).unwrap();
+  writeln!(json, "  \"files\": {},", list(files.iter().map(|(file, rows, bytes)| format!("{{\"name\": {}, \"rows\": {}, \"bytes\": {}}}", json_string(file), rows, bytes)).collect())).unwrap();
+  writeln!(json, "  \"bytes\": {},", files.iter().map(|(_, _, bytes)| bytes).sum::<u64>()).unwrap();
+  writeln!(json, "  \"elapsed\": {:.3}", elapsed).unwrap();
+  json.push_str("}\n");
+  json
+}
+fn table_stats(table: &Table, tables: &mut Vec<String>, filtered: &mut Vec<String>) { // Walks the tables in the same order as explain_table()
+  if table.cardinality != Cardinality::None {
+    tables.push(format!("{{\"name\": {}, \"file\": {}, \"rows\": {}}}", json_string(&table.name), table.file.as_deref().map_or(String::from("null"), json_string), table.rows.get()));
+  }
+  for col in &table.columns {
+    if col.include.is_some() || col.exclude.is_some() { filtered.push(format!("{}: {}", json_string(&format!("{}.{}", table.name, col.name)), col.filtered.get())); }
+  }
+  for col in &table.columns {
+    if let Some(domain) = &col.domain { table_stats(&domain.borrow().table, tables, filtered); }
+    if let Some(subtable) = &col.subtable {
+      table_stats(subtable, tables, filtered);
+      if let Some(domain) = subtable.domain.as_ref() { table_stats(&domain.borrow().table, tables, filtered); }
+    }
+  }
+}
+fn write_reject(mut rejects: &File, table: &Table, reason: &str, filename: &str, position: u64, settings: &Settings) {
+  // Rejected rows are written in text COPY format regardless of the 'format' setting, with the row as a JSON object
+  let mut row = String::from("{");
+  for col in table.columns.iter().filter(|c| c.subtable.is_none()) {
+    if row.len() > 1 { row.push_str(", "); }
+    let value = col.value.borrow();
+    let value = match settings.format {
+      Format::Text => Cow::Owned(unescape_text(&value)),
+      _ => Cow::Borrowed(value.as_str())
+    };
+    write!(row, "{}: {}", json_string(&col.name), if value.is_empty() { String::from("null") } else { json_string(&value) }).unwrap();
+  }
+  row.push('}');
+  let escape = |value: &str| value.cow_replace("\\", "\\\\").cow_replace("\t", "\\t").cow_replace("\n", "\\n").cow_replace("\r", "\\r").into_owned();
+  // Unbuffered, so the rejects up to a fatal error are kept
+  rejects.write_all(format!("{}\t{}\t{}\t{}\t{}\n", escape(filename), position, escape(&table.name), escape(reason), escape(&row)).as_bytes()).unwrap_or_else(|err| fatalerr!("Error: failed to write rejects file: {}", err));
+}
+fn unescape_text(value: &str) -> String { // Undoes the escaping of text COPY format
+  let mut result = String::with_capacity(value.len());
+  let mut chars = value.chars();
+  while let Some(c) = chars.next() {
+    if c != '\\' { result.push(c); continue; }
+    match chars.next() {
+      Some('t') => result.push('\t'),
+      Some('n') => result.push('\n'),
+      Some('r') => result.push('\r'),
+      Some(c) => result.push(c),
+      None => ()
+    }
+  }
+  result
+}
+fn json_string(value: &str) -> String {
+  let mut result = String::from("\"");
+  for c in value.chars() {
+    match c {
+      '"' => result.push_str("\\\""),
+      '\\' => result.push_str("\\\\"),
+      '\n' => result.push_str("\\n"),
+      '\r' => result.push_str("\\r"),
+      '\t' => result.push_str("\\t"),
+      c if (c as u32) < 0x20 => write!(result, "\\u{:04x}", c as u32).unwrap(),
+      c => result.push(c)
+    }
+  }
+  result.push('"');
+  result
+}
+
+fn defer_event(state: &mut State, event: &Event) -> Step {
+  state.deferrals.last_mut().unwrap().events.push((state.path.clone(), event.clone().into_owned()));
+  Step::Defer
+}
+fn handle_event(event: &Event, state: &mut State, first: usize) -> Step { // Processes an event for the current table and the forks from index 'first'
+  let mut forks = state.forks.split_off(first);
+  let step = state.step; // Repeat when the path already includes the element of a start event
+  forks.retain_mut(|fork| run_fork(event, state, fork, step));
+  let created = state.forks.len();
+  loop { // Repeat loop to be able to process a node twice
+    state.step = process_event(event, state);
+    match state.step {
+      Step::Next | Step::Defer => break,
+      Step::Repeat => continue,
+      Step::Apply => apply_deferred(state), // Then process the end of the row again
+      Step::Done => return Step::Done
+    }
+  }
+  if state.step == Step::Defer { state.forks.truncate(created); } // They start again when the deferred events are applied
+  loop { // New forks process their start event from the subtable on
+    let fresh = state.forks.split_off(first);
+    if fresh.is_empty() { break; }
+    for mut fork in fresh {
+      if run_fork(event, state, &mut fork, Step::Repeat) { forks.push(fork); }
+    }
+  }
+  state.forks.append(&mut forks);
+  Step::Next
+}
+fn run_fork<'a, 'b>(event: &Event, state: &mut State<'a, 'b>, fork: &mut Fork<'a, 'b>, step: Step) -> bool { // Returns whether the fork is still active
+  fork.swap(state); // Including the deferrals, so a fork defers its events separately from the current table
+  let path = state.path.clone();
+  let prevstep = mem::replace(&mut state.step, step);
+  let created = state.forks.len();
+  let active = loop {
+    state.step = process_event(event, state);
+    if state.tables.len() < fork.base { break false; } // Back at the parent table
+    match state.step {
+      Step::Repeat => continue,
+      Step::Apply => apply_deferred(state), // Then process the end of the row again
+      Step::Defer => {
+        state.forks.truncate(created); // They start again when the deferred events are applied
+        break true;
+      },
+      _ => break true
+    }
+  };
+  state.step = prevstep;
+  state.path = path;
+  fork.swap(state);
+  active
+}
+fn apply_deferred(state: &mut State) {
+  let deferral = state.deferrals.pop().unwrap();
+  if deferral.table.lastid.borrow().is_empty() && state.rejects.is_none() { // Otherwise the subtable rows go to the rejects file
+    match deferral.table.badkey.borrow().as_ref() {
+      Some((Mismatch::Skip, _)) => return, // Skipped along with their parent row
+      Some(_) => (), // Written with a null foreign key, as the key of the parent row is set to null
+      None => fatalerr!("Error: table {} has subtable rows preceding its key, but the key was never found", deferral.table.name)
+    }
+  }
+  let path = mem::take(&mut state.path);
+  let nsbindings = state.nsbindings.clone();
+  let replay = state.replay.replace(deferral.table);
+  let forks = state.forks.len(); // Those active now have seen these events already
+  for (evpath, event) in &deferral.events {
+    state.path.clone_from(evpath);
+    state.step = Step::Repeat; // The path already includes the element of a start event
+    handle_event(event, state, forks);
+  }
+  state.replay = replay;
+  state.nsbindings = nsbindings;
+  state.path = path;
+}
+
+fn bind_namespaces(e: &BytesStart, depth: usize, decoder: Decoder, bindings: &mut Vec<(usize, String, String)>) {
+  // Bindings are kept with the depth of the element declaring them, so they go out of scope with it
+  while let Some((d, _, _)) = bindings.last() {
+    if *d < depth { break; }
+    bindings.pop();
+  }
+  for attr in e.attributes().flatten() {
+    let prefix = match attr.key.as_ref() {
+      b"xmlns" => Cow::Borrowed(""),
+      key if key.starts_with(b"xmlns:") => decoder.decode(&key[6..]).unwrap_or_else(|err| fatalerr!("Error: failed to decode XML namespace prefix '{}': {}", String::from_utf8_lossy(key), err)),
+      _ => continue
+    };
+    let uri = decoder.decode(&attr.value).unwrap_or_else(|err| fatalerr!("Error: failed to decode XML namespace '{}': {}", String::from_utf8_lossy(&attr.value), err));
+    bindings.push((depth, prefix.into_owned(), uri.into_owned()));
+  }
+}
+fn namespace_uri<'a>(state: &'a State, prefix: &str) -> Option<&'a str> {
+  state.nsbindings.iter().rev().find(|(_, p, _)| p == prefix).map(|(_, _, uri)| uri.as_str())
+}
+fn resolve_name<'e>(state: &State, qname: &'e str, attribute: bool) -> Cow<'e, str> {
+  let (prefix, local) = qname.split_once(':').unwrap_or(("", qname));
+  if state.settings.ns_ignore { return Cow::Borrowed(local); }
+  if state.settings.namespaces.is_empty() || (attribute && prefix.is_empty()) { return Cow::Borrowed(qname); } // Unprefixed attributes have no namespace
+  match namespace_uri(state, prefix).and_then(|uri| state.settings.namespaces.get(uri)) {
+    Some(mapped) if mapped.is_empty() => Cow::Borrowed(local),
+    Some(mapped) => Cow::Owned(format!("{}:{}", mapped, local)),
+    None => Cow::Borrowed(qname)
+  }
+}
+fn gml_name<'e>(state: &State, qname: &'e str) -> Option<&'e str> {
+  let (prefix, local) = qname.split_once(':').unwrap_or(("", qname));
+  if state.settings.ns_ignore { return Some(local); }
+  match namespace_uri(state, prefix) {
+    Some(uri) if uri.starts_with("http://www.opengis.net/gml") => Some(local),
+    Some(_) => None,
+    None if prefix == "gml" => Some(local), // Namespace unknown or not resolved; go by the conventional prefix
+    None => None
+  }
+}
+
 fn path_match(path: &String, mask: &String) -> bool {
   if !mask.contains("*") && !mask.contains("{") { return path == mask; }
   glob_match(mask, path)
 }
 
+fn path_within(path: &str, mask: &String) -> bool { // Whether the path lies inside an element matching the mask
+  match path.match_indices('/').nth(mask.matches('/').count()) {
+    Some((i, _)) => path_match(&path[..i].to_owned(), mask),
+    None => false
+  }
+}
+
 fn allow_iteration(column: &Column, settings: &Settings) -> bool {
   match column.aggr {
-    None if settings.hush_warning => false,
     None => {
-      eprintln!("Warning: column '{}' has multiple occurrences without an aggregation method; using 'first'", column.name);
+      warning!(settings.hush_warning, "aggregation", "Warning: column '{}' has multiple occurrences without an aggregation method; using 'first'", column.name);
       false
     },
     Some("first") => false,
@@ -1146,9 +2699,221 @@ fn allow_iteration(column: &Column, settings: &Settings) -> bool {
   }
 }
 
-fn write_output(file: RefCell<Box<dyn Write>>, rx: mpsc::Receiver<String>) {
+fn write_database(mut client: Client, rx: mpsc::Receiver<Vec<u8>>, name: &str, transactions: Arc<Mutex<Vec<Client>>>) {
+  let script = String::from_utf8(rx.recv().unwrap_or_default()).unwrap(); // Any statements from the 'emit' option, ending with the COPY
+  let (statements, copy) = script.trim_end().rsplit_once('\n').unwrap_or(("", script.trim_end()));
+  if copy.is_empty() { return; }
+  client.batch_execute(&format!("BEGIN;\n{}", statements)).unwrap_or_else(|err| fatalerr!("Error: failed to prepare table {} in database: {}", name, db_error(&err)));
+  let mut writer = client.copy_in(copy).unwrap_or_else(|err| fatalerr!("Error: failed to start COPY into table {}: {}", name, db_error(&err)));
+  while let Ok(buf) = rx.recv() {
+    if buf.is_empty() { break; }
+    writer.write_all(&buf).unwrap_or_else(|err| fatalerr!("Error: failed to send rows for table {} to database: {}", name, err));
+  }
+  writer.finish().unwrap_or_else(|err| fatalerr!("Error: failed to COPY rows into table {}: {}", name, db_error(&err)));
+  let statements = String::from_utf8(rx.recv().unwrap_or_default()).unwrap(); // Any statements to run after the COPY
+  if !statements.is_empty() { client.batch_execute(&statements).unwrap_or_else(|err| fatalerr!("Error: failed to finish table {} in database: {}", name, db_error(&err))); }
+  transactions.lock().unwrap().push(client);
+}
+fn commit_database(transactions: &mut [Client], twophase: bool) { // Two-phase commit, so the tables are committed all together or not at all
+  if !twophase {
+    for i in 0..transactions.len() {
+      if let Err(err) = transactions[i].batch_execute("COMMIT") { fatalerr!("Error: failed to commit transaction: {}{}", db_error(&err), if i > 0 { format!("; {} of the {} tables were already committed", i, transactions.len()) } else { String::new() }); }
+    }
+    return;
+  }
+  let stamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+  let gids = (0..transactions.len()).map(|i| format!("xml-to-postgres-{}-{}-{}", stamp, std::process::id(), i)).collect::<Vec<String>>();
+  for i in 0..transactions.len() {
+    if let Err(err) = transactions[i].batch_execute(&format!("PREPARE TRANSACTION '{}'", gids[i])) {
+      for j in 0..i { // Prepared transactions outlive their connection, so they need to be rolled back explicitly
+        if let Err(err) = transactions[j].batch_execute(&format!("ROLLBACK PREPARED '{}'", gids[j])) {
+          eprintln!("Error: failed to roll back prepared transaction '{}': {}", gids[j], db_error(&err));
+        }
+      }
+      fatalerr!("Error: failed to prepare transaction for commit (the server needs max_prepared_transactions of at least {}): {}", transactions.len(), db_error(&err));
+    }
+  }
+  for i in 0..transactions.len() {
+    if let Err(err) = transactions[i].batch_execute(&format!("COMMIT PREPARED '{}'", gids[i])) {
+      fatalerr!("Error: failed to commit prepared transaction: {}; the transactions {} are still prepared and can be finished with COMMIT PREPARED", db_error(&err), gids[i..].join(", "));
+    }
+  }
+}
+fn db_error(err: &postgres::Error) -> String {
+  match (err.as_db_error(), std::error::Error::source(err)) {
+    (Some(dberr), _) => format!("{}{}", dberr.message(), dberr.where_().map_or(String::new(), |context| format!(" ({})", context))),
+    (None, Some(source)) => format!("{}: {}", err, source),
+    (None, None) => err.to_string()
+  }
+}
+fn write_output(file: RefCell<Box<dyn Write>>, rx: mpsc::Receiver<Vec<u8>>, written: Arc<AtomicU64>) {
   while let Ok(buf) = rx.recv() {
-    if buf.len() == 0 { break; }
-    file.borrow_mut().write_all(buf.as_bytes()).unwrap_or_else(|err| fatalerr!("Error: IO error encountered while writing table: {}", err))
+    if buf.is_empty() { break; }
+    file.borrow_mut().write_all(&buf).unwrap_or_else(|err| fatalerr!("Error: IO error encountered while writing table: {}", err));
+    written.fetch_add(1, Ordering::Release);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn encoded(value: &str, bintype: BinaryType) -> Option<String> { // As hex, like the output of the *_send functions in PostgreSQL
+    let mut buf = Vec::new();
+    encode_binary(&mut buf, value, bintype).then(|| buf.iter().map(|b| format!("{:02x}", b)).collect())
+  }
+
+  #[test]
+  fn binary_numeric() {
+    for (value, hex) in [
+      ("0", "0000000000000000"),
+      ("-0", "0000000000000000"),
+      ("0.00", "0000000000000002"),
+      ("-0.5", "0001ffff400000011388"),
+      (".5", "0001ffff000000011388"),
+      ("0.0001", "0001ffff000000040001"),
+      ("12345.678", "0003000100000003000109291a7c"),
+      ("-123456789.0001", "0004000240000004000109291a850001"),
+      ("99999999", "0002000100000000270f270f"),
+      ("1E+5", "0001000100000000000a"),
+      ("1e100", "00010019000000000001"),
+      ("1.5e-10", "0001fffd0000000b0096"),
+      ("1e-1000", "0001ff06000003e80001"),
+      ("1e99999", "000161a70000000003e8"),
+      ("1e-16383", "0001f00000003fff000a"),
+      ("NaN", "00000000c0000000")
+    ] {
+      assert_eq!(encoded(value, BinaryType::Numeric).as_deref(), Some(hex), "numeric {}", value);
+    }
+    for value in ["", ".", "-", "1.2.3", "1e", "1e123456", "1e-16384", "0x10", "1,5"] {
+      assert_eq!(encoded(value, BinaryType::Numeric), None, "numeric {}", value);
+    }
+  }
+
+  #[test]
+  fn binary_datetime() {
+    for (value, date, timestamp, timestamptz) in [
+      ("2000-01-01", "00000000", "0000000000000000", "0000000000000000"),
+      ("1999-12-31 23:59:59.5", "ffffffff", "fffffffffff85ee0", "fffffffffff85ee0"),
+      ("1970-01-01T00:00:00Z", "ffffd533", "fffca2fec4c82000", "fffca2fec4c82000"),
+      ("2024-02-29 12:00:00+02:00", "00002279", "0002b582c4781000", "0002b5811750c800"),
+      ("1900-03-01 00:00:00-05:30", "ffff718f", "fff4ce915caea000", "fff4ce95f8daa600"),
+      ("1600-02-29 06:07:08.123456", "fffdc58a", "ffd32c4dd3f43d40", "ffd32c4dd3f43d40"),
+      ("0001-01-01", "fff4dbf9", "ff1fe2ffc59c6000", "ff1fe2ffc59c6000")
+    ] {
+      assert_eq!(encoded(value, BinaryType::Date).as_deref(), Some(date), "date {}", value);
+      assert_eq!(encoded(value, BinaryType::Timestamp).as_deref(), Some(timestamp), "timestamp {}", value);
+      assert_eq!(encoded(value, BinaryType::TimestampTz).as_deref(), Some(timestamptz), "timestamptz {}", value);
+    }
+    assert_eq!(encoded("infinity", BinaryType::Timestamp).as_deref(), Some("7fffffffffffffff"));
+    assert_eq!(encoded("-infinity", BinaryType::Date).as_deref(), Some("80000000"));
+    for value in ["1900-02-29", "2023-13-01", "2023-01-01 24:00", "01-01-2023", "2023-01-01 12:00 CET"] {
+      assert_eq!(encoded(value, BinaryType::Timestamp), None, "timestamp {}", value);
+    }
+  }
+
+  #[test]
+  fn binary_other() {
+    assert_eq!(encoded(" -2 ", BinaryType::Int2).as_deref(), Some("fffe"));
+    assert_eq!(encoded("40000", BinaryType::Int2), None);
+    assert_eq!(encoded("2147483647", BinaryType::Int4).as_deref(), Some("7fffffff"));
+    assert_eq!(encoded("1", BinaryType::Int8).as_deref(), Some("0000000000000001"));
+    assert_eq!(encoded("1.5", BinaryType::Int8), None);
+    assert_eq!(encoded("1.5", BinaryType::Float8).as_deref(), Some("3ff8000000000000"));
+    assert_eq!(encoded("-2", BinaryType::Float4).as_deref(), Some("c0000000"));
+    assert_eq!(encoded("Yes", BinaryType::Bool).as_deref(), Some("01"));
+    assert_eq!(encoded("off", BinaryType::Bool).as_deref(), Some("00"));
+    assert_eq!(encoded("maybe", BinaryType::Bool), None);
+    assert_eq!(encoded("{A0EEBC99-9C0B-4EF8-BB6D-6BB9BD380A11}", BinaryType::Uuid).as_deref(), Some("a0eebc999c0b4ef8bb6d6bb9bd380a11"));
+    assert_eq!(encoded("a0eebc99", BinaryType::Uuid), None);
+    assert_eq!(encoded("\\x00ff", BinaryType::Bytea).as_deref(), Some("00ff"));
+    assert_eq!(encoded("\\x0", BinaryType::Bytea), None);
+    assert_eq!(encoded("{}", BinaryType::Jsonb).as_deref(), Some("017b7d"));
+  }
+  fn checked(value: &str, bintype: BinaryType, formats: &[&'static str], mismatch: Mismatch) -> (Result<(), Mismatch>, String) {
+    let col = Column { name: String::from("c"), check: Some((bintype, mismatch)), formats: formats.to_vec(), value: RefCell::new(value.to_owned()), ..Default::default() };
+    let result = col.check().map_err(|(mismatch, reason)| {
+      assert_eq!(reason, format!("column c has value '{}' that does not match its type", value));
+      mismatch
+    });
+    (result, col.value.take())
+  }
+
+  #[test]
+  fn check_types() {
+    for (value, bintype, formats, normalized) in [
+      ("Yes", BinaryType::Bool, &[][..], Some("t")),
+      ("OFF", BinaryType::Bool, &[], Some("f")),
+      ("maybe", BinaryType::Bool, &[], None),
+      (" 42 ", BinaryType::Int2, &[], Some("42")),
+      ("+7", BinaryType::Int4, &[], Some("7")),
+      ("-32768", BinaryType::Int2, &[], Some("-32768")),
+      ("32768", BinaryType::Int2, &[], None),
+      ("2147483648", BinaryType::Int4, &[], None),
+      ("2147483648", BinaryType::Int8, &[], Some("2147483648")),
+      ("1.5", BinaryType::Int8, &[], None),
+      ("1.5e3", BinaryType::Float8, &[], Some("1.5e3")),
+      ("-12.50", BinaryType::Numeric, &[], Some("-12.50")),
+      ("12,50", BinaryType::Numeric, &[], None),
+      ("2024-02-29", BinaryType::Date, &[], Some("2024-02-29")),
+      ("2023-02-29", BinaryType::Date, &[], None),
+      ("2024-01-31T10:00:00+01:00", BinaryType::TimestampTz, &[], Some("2024-01-31T10:00:00+01:00")),
+      ("infinity", BinaryType::Timestamp, &[], Some("infinity")),
+      ("31-01-2024", BinaryType::Date, &[], None),
+      ("31-01-2024", BinaryType::Date, &["%d-%m-%Y"], Some("2024-01-31")),
+      ("31/01/2024 10:15", BinaryType::Timestamp, &["%d-%m-%Y", "%d/%m/%Y %H:%M"], Some("2024-01-31 10:15:00")),
+      ("31/01/2024 10:15 +0100", BinaryType::Timestamp, &["%d/%m/%Y %H:%M %z"], Some("2024-01-31 10:15:00")),
+      ("31/01/2024 10:15 +0100", BinaryType::TimestampTz, &["%d/%m/%Y %H:%M %z"], Some("2024-01-31 10:15:00+01:00")),
+      ("2024-01-31", BinaryType::Date, &["%d-%m-%Y"], None)
+    ] {
+      assert_eq!(check_value(value, bintype, formats).as_deref(), normalized, "{:?} {}", bintype, value);
+    }
+  }
+
+  #[test]
+  fn check_policies() {
+    assert_eq!(checked("on", BinaryType::Bool, &[], Mismatch::Skip), (Ok(()), String::from("t")));
+    assert_eq!(checked("", BinaryType::Int4, &[], Mismatch::Skip), (Ok(()), String::new())); // NULL is not checked
+    assert_eq!(checked("x", BinaryType::Int4, &[], Mismatch::Error), (Err(Mismatch::Error), String::from("x")));
+    assert_eq!(checked("x", BinaryType::Int4, &[], Mismatch::Null), (Err(Mismatch::Null), String::new()));
+    assert_eq!(checked("x", BinaryType::Int4, &[], Mismatch::Skip), (Err(Mismatch::Skip), String::from("x"))); // Kept for the rejects file
+    let col = Column { value: RefCell::new(String::from("x")), ..Default::default() }; // No 'fail' option
+    assert_eq!(col.check(), Ok(()));
+  }
+
+  fn written(values: &[&str], format: Format, delimiter: u8) -> Vec<u8> {
+    let settings = Settings { filemode: String::new(), zipglob: String::new(), encoding: None, namespaces: HashMap::new(), ns_ignore: false, format, delimiter, header: false, mismatch: None, spill: None, explain: false, resume: false, connection: None, transactions: Arc::new(Mutex::new(Vec::new())), emit_copyfrom: false, emit_createtable: false, emit_starttransaction: false, emit_truncate: false, emit_droptable: false, emit_identity: false, hush_version: true, hush_info: true, hush_notice: true, hush_warning: true, show_progress: false };
+    let mut buf = Vec::new();
+    let mut row = Row::new(&buf);
+    for value in values { row.field(&mut buf, value, None, &settings); }
+    row.end(&mut buf, &settings);
+    buf
+  }
+
+  #[test]
+  fn csv_quoting() {
+    for (value, field) in [
+      ("plain", "plain"),
+      ("a b;c\\d\t", "a b;c\\d\t"),
+      ("a,b", "\"a,b\""),
+      ("say \"hi\"", "\"say \"\"hi\"\"\""),
+      ("\"", "\"\"\"\""),
+      ("\"\"", "\"\"\"\"\"\""), // Two quotes, not an empty string
+      ("line\nbreak", "\"line\nbreak\""),
+      ("carriage\rreturn", "\"carriage\rreturn\""),
+      ("\\.", "\"\\.\""), // Would end the data on a line of its own
+      ("\\.x", "\\.x"),
+      ("", "") // Unquoted, so NULL, like \N in text format
+    ] {
+      assert_eq!(String::from_utf8(written(&[value], Format::Csv, b',')).unwrap(), format!("{}\n", field), "csv {:?}", value);
+    }
+    assert_eq!(written(&["1", "", "a,b", "x;y", ""], Format::Csv, b','), b"1,,\"a,b\",x;y,\n");
+    assert_eq!(written(&["1", "", "a,b", "x;y", ""], Format::Csv, b';'), b"1;;a,b;\"x;y\";\n");
+  }
+
+  #[test]
+  fn null_fields() {
+    assert_eq!(written(&["", "a", ""], Format::Text, b'\t'), b"\\N\ta\t\\N\n");
+    assert_eq!(written(&["", "1"], Format::Binary, b'\t'), b"\x00\x02\xff\xff\xff\xff\x00\x00\x00\x04\x00\x00\x00\x01");
   }
 }
@@ -0,0 +1,266 @@
+// Generates a starter configuration file from the structure of a sample XML file
+
+use std::collections::HashSet;
+use std::io::{BufRead, Write};
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use quick_xml::Reader;
+use quick_xml::events::Event;
+use yaml_rust2::{Yaml, YamlEmitter};
+use yaml_rust2::yaml::Hash;
+use crate::{parse_timestamp, Inputs};
+
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+enum Guess {
+  #[default]
+  Unknown,
+  Boolean,
+  Integer,
+  BigInt,
+  Numeric,
+  Date,
+  Timestamp,
+  TimestampTz,
+  Text
+}
+impl Guess {
+  fn from(value: &str) -> Guess {
+    if value == "true" || value == "false" { return Guess::Boolean; }
+    if let Ok(int) = value.parse::<i64>() {
+      return if i32::try_from(int).is_ok() { Guess::Integer } else { Guess::BigInt };
+    }
+    if value.parse::<f64>().is_ok() && value.trim_start_matches(['-', '+']).starts_with(|c: char| c.is_ascii_digit() || c == '.') { return Guess::Numeric; }
+    if value.len() >= 10 && parse_timestamp(value).is_some() {
+      if value.len() == 10 { return Guess::Date; }
+      if value.ends_with('Z') || value[10..].contains(['+', '-']) { return Guess::TimestampTz; }
+      return Guess::Timestamp;
+    }
+    Guess::Text
+  }
+  fn merge(self, other: Guess) -> Guess { // Widens the guess to a type that fits the values of both
+    match (self, other) {
+      (a, b) if a == b => a,
+      (Guess::Unknown, b) => b,
+      (a, Guess::Unknown) => a,
+      (Guess::Integer | Guess::BigInt, Guess::Integer | Guess::BigInt) => Guess::BigInt,
+      (Guess::Integer | Guess::BigInt | Guess::Numeric, Guess::Integer | Guess::BigInt | Guess::Numeric) => Guess::Numeric,
+      (Guess::Date | Guess::Timestamp, Guess::Date | Guess::Timestamp) => Guess::Timestamp,
+      (Guess::Date | Guess::Timestamp | Guess::TimestampTz, Guess::Date | Guess::Timestamp | Guess::TimestampTz) => Guess::TimestampTz,
+      _ => Guess::Text
+    }
+  }
+  fn datatype(self) -> Option<&'static str> {
+    match self {
+      Guess::Unknown | Guess::Text => None,
+      Guess::Boolean => Some("boolean"),
+      Guess::Integer => Some("integer"),
+      Guess::BigInt => Some("bigint"),
+      Guess::Numeric => Some("numeric"),
+      Guess::Date => Some("date"),
+      Guess::Timestamp => Some("timestamp"),
+      Guess::TimestampTz => Some("timestamptz")
+    }
+  }
+}
+
+#[derive(Default)]
+struct Node {
+  name: String,
+  children: Vec<Node>,
+  attributes: Vec<(String, Guess)>,
+  count: usize,
+  seen: usize,     // Occurrences within the current instance of the parent element
+  maxrepeat: usize,
+  text: Guess
+}
+impl Node {
+  fn child(&mut self, name: &str) -> &mut Node {
+    let i = match self.children.iter().position(|c| c.name == name) {
+      Some(i) => i,
+      None => {
+        self.children.push(Node { name: name.to_owned(), ..Default::default() });
+        self.children.len()-1
+      }
+    };
+    &mut self.children[i]
+  }
+  fn descend(&mut self, stack: &[usize]) -> &mut Node {
+    stack.iter().fold(self, |node, i| &mut node.children[*i])
+  }
+  fn find(&self, path: &str) -> Option<&Node> {
+    path.split('/').filter(|s| !s.is_empty()).try_fold(self, |node, name| node.children.iter().find(|c| c.name == name))
+  }
+  fn is_geometry(&self) -> bool { // Element containing a GML geometry supported by gml-to-ewkb
+    self.children.iter().any(|c| matches!(c.name.split_once(':'), Some(("gml", "Point" | "LineString" | "Polygon" | "MultiPolygon"))))
+  }
+}
+
+pub fn scan(input: &str, rowpath: Option<&str>) {
+  let mut root = Node::default();
+  let mut stack: Vec<usize> = Vec::new();
+  let mut buf = Vec::new();
+  let mut elements = 0;
+  let inputs = Inputs { paths: vec![input.to_owned()].into_iter(), read: Arc::new(AtomicU64::new(0)), members: None, zipglob: String::from("**/*.{xml,XML}"), encoding: None, hush_warning: false, skip: 0 };
+  for (filename, mut reader) in inputs {
+    loop {
+      match reader.read_event_into(&mut buf).unwrap_or_else(|e| fatalerr!("Error: failed to parse XML in {} at position {}: {}", filename, reader.buffer_position(), e)) {
+        Event::Start(e) => {
+          elements += 1;
+          let name = decode(&reader, e.name().as_ref());
+          let parent = root.descend(&stack);
+          let child = parent.child(&name);
+          child.count += 1;
+          child.seen += 1;
+          child.maxrepeat = child.maxrepeat.max(child.seen);
+          for grandchild in child.children.iter_mut() { grandchild.seen = 0; }
+          for attr in e.attributes().flatten() {
+            let key = decode(&reader, attr.key.as_ref());
+            if key == "xmlns" || key.starts_with("xmlns:") { continue; }
+            let guess = Guess::from(&attr.decode_and_unescape_value(reader.decoder()).unwrap_or_default());
+            match child.attributes.iter_mut().find(|(name, _)| *name == key) {
+              Some(entry) => entry.1 = entry.1.merge(guess),
+              None => child.attributes.push((key, guess))
+            }
+          }
+          stack.push(parent.children.iter().position(|c| c.name == name).unwrap());
+        },
+        Event::End(_) => { stack.pop(); },
+        Event::Text(ref e) => {
+          let text = e.unescape().unwrap_or_default();
+          if text.trim().is_empty() { continue; }
+          let node = root.descend(&stack);
+          node.text = node.text.merge(Guess::from(text.trim()));
+        },
+        Event::CData(ref e) => {
+          let node = root.descend(&stack);
+          node.text = node.text.merge(Guess::from(String::from_utf8_lossy(e).trim()));
+        },
+        Event::Eof => break,
+        _ => ()
+      }
+      buf.clear();
+    }
+  }
+
+  let rowpath = match rowpath {
+    Some(path) => path.trim_end_matches('/').to_owned(),
+    None => find_rowpath(&root).unwrap_or_else(|| fatalerr!("Error: no repeating elements found in {}; pass the path of the row element as an extra argument", input))
+  };
+  let row = root.find(&rowpath).unwrap_or_else(|| fatalerr!("Error: path {} not found in {}", rowpath, input));
+  eprintln!("Info: scanned {} elements; using {} ({} rows) as the main table", elements, rowpath, row.count);
+
+  let name = colname(&row.name);
+  let mut config = Hash::new();
+  config.insert(Yaml::from_str("name"), Yaml::String(name.clone()));
+  config.insert(Yaml::from_str("path"), Yaml::String(rowpath.clone()));
+  config.insert(Yaml::from_str("file"), Yaml::String(format!("{}.dump", name)));
+  config.insert(Yaml::from_str("emit"), Yaml::from_str("create_table"));
+  config.insert(Yaml::from_str("cols"), Yaml::Array(table_columns(row)));
+  print_config(&Yaml::Hash(config), &format!("sample {}", input));
+}
+
+fn decode<R: BufRead>(reader: &Reader<R>, bytes: &[u8]) -> String {
+  reader.decoder().decode(bytes).unwrap_or_else(|err| fatalerr!("Error: failed to decode XML name '{}': {}", String::from_utf8_lossy(bytes), err)).into_owned()
+}
+
+fn find_rowpath(root: &Node) -> Option<String> { // The shallowest repeating element; the most frequent one if there are several
+  let mut level: Vec<(String, &Node)> = root.children.iter().map(|c| (format!("/{}", c.name), c)).collect();
+  while !level.is_empty() {
+    if let Some((path, _)) = level.iter().filter(|(_, node)| node.maxrepeat > 1).max_by_key(|(_, node)| node.count) {
+      return Some(path.clone());
+    }
+    level = level.iter().flat_map(|(path, node)| node.children.iter().map(move |c| (format!("{}/{}", path, c.name), c))).collect();
+  }
+  None
+}
+
+fn table_columns(node: &Node) -> Vec<Yaml> {
+  let mut cols = Vec::new();
+  let mut names = HashSet::new();
+  for (attr, guess) in &node.attributes {
+    cols.push(Yaml::Hash(column(unique_name(attr, "", &mut names), "/", Some(attr), guess.datatype())));
+  }
+  if node.children.is_empty() && node.text != Guess::Unknown {
+    cols.push(Yaml::Hash(column(unique_name(&node.name, "", &mut names), "/", None, node.text.datatype())));
+  }
+  child_columns(node, "", &mut cols, &mut names);
+  key_column(&mut cols, &mut names);
+  cols
+}
+
+pub fn key_column(cols: &mut Vec<Yaml>, names: &mut HashSet<String>) { // A subtable can't be the first column, as that serves as the key
+  if cols.first().is_none_or(|col| !col["file"].is_badvalue()) {
+    let mut col = Hash::new();
+    col.insert(Yaml::from_str("name"), Yaml::String(unique_name("id", "", names)));
+    col.insert(Yaml::from_str("seri"), Yaml::Boolean(true));
+    cols.insert(0, Yaml::Hash(col));
+  }
+}
+
+fn child_columns(node: &Node, prefix: &str, cols: &mut Vec<Yaml>, names: &mut HashSet<String>) {
+  for child in &node.children {
+    let path = if prefix.is_empty() { child.name.clone() } else { format!("{}/{}", prefix, child.name) };
+    if child.maxrepeat > 1 { // Repeating element; becomes a one-to-many subtable
+      let name = unique_name(&child.name, &path, names);
+      let single = child.children.is_empty() && child.attributes.is_empty();
+      let mut col = column(name.clone(), &path, None, if single { child.text.datatype() } else { None });
+      col.insert(Yaml::from_str("file"), Yaml::String(format!("{}.dump", name)));
+      if !single { col.insert(Yaml::from_str("cols"), Yaml::Array(table_columns(child))); }
+      cols.push(Yaml::Hash(col));
+    }
+    else if child.is_geometry() {
+      let mut col = column(unique_name(&child.name, &path, names), &path, None, None);
+      col.insert(Yaml::from_str("conv"), Yaml::from_str("gml-to-ewkb"));
+      cols.push(Yaml::Hash(col));
+    }
+    else {
+      for (attr, guess) in &child.attributes {
+        cols.push(Yaml::Hash(column(unique_name(&format!("{}_{}", local_name(&child.name), attr), &format!("{}/{}", path, attr), names), &path, Some(attr), guess.datatype())));
+      }
+      if child.text != Guess::Unknown || (child.children.is_empty() && child.attributes.is_empty()) {
+        cols.push(Yaml::Hash(column(unique_name(&child.name, &path, names), &path, None, child.text.datatype())));
+      }
+      child_columns(child, &path, cols, names);
+    }
+  }
+}
+
+pub fn column(name: String, path: &str, attr: Option<&str>, datatype: Option<&str>) -> Hash {
+  let mut col = Hash::new();
+  col.insert(Yaml::from_str("name"), Yaml::String(name));
+  col.insert(Yaml::from_str("path"), Yaml::String(path.to_owned()));
+  if let Some(attr) = attr { col.insert(Yaml::from_str("attr"), Yaml::String(attr.to_owned())); }
+  if let Some(datatype) = datatype { col.insert(Yaml::from_str("type"), Yaml::from_str(datatype)); }
+  col
+}
+
+pub fn local_name(name: &str) -> &str {
+  name.rsplit(':').next().unwrap()
+}
+
+pub fn colname(name: &str) -> String { // Lowercase identifier that needs no quoting in PostgreSQL
+  let mut ident: String = local_name(name).chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect();
+  if !ident.starts_with(|c: char| c.is_ascii_lowercase() || c == '_') { ident.insert(0, '_'); }
+  ident
+}
+
+pub fn unique_name(name: &str, path: &str, names: &mut HashSet<String>) -> String { // Falls back to the full path, then a number, to avoid duplicates
+  let mut candidate = colname(name);
+  if names.contains(&candidate) && !path.is_empty() { candidate = path.split('/').map(colname).collect::<Vec<String>>().join("_").replace("__", "_"); }
+  let base = candidate.clone();
+  let mut n = 1;
+  while names.contains(&candidate) {
+    n += 1;
+    candidate = format!("{}_{}", base, n);
+  }
+  names.insert(candidate.clone());
+  candidate
+}
+
+pub fn print_config(config: &Yaml, source: &str) {
+  let mut out = String::new();
+  YamlEmitter::new(&mut out).dump(config).unwrap_or_else(|err| fatalerr!("Error: failed to generate configuration: {}", err));
+  let mut stdout = std::io::stdout();
+  writeln!(stdout, "# Generated from {}; review the names, types and keys before use", source).unwrap();
+  writeln!(stdout, "{}", out.trim_start_matches("---\n")).unwrap_or_else(|err| fatalerr!("Error: failed to write configuration: {}", err));
+}
@@ -0,0 +1,436 @@
+// Generates a configuration file from the XML Schema (XSD) definition of the input
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use quick_xml::events::Event;
+use yaml_rust2::Yaml;
+use yaml_rust2::yaml::Hash;
+use crate::scan::{colname, column, key_column, local_name, print_config, unique_name};
+use crate::xml_reader;
+
+const XSD_NS: &str = "http://www.w3.org/2001/XMLSchema";
+const GML_NS: &str = "http://www.opengis.net/gml";
+
+#[derive(Default)]
+struct Node {
+  name: String, // Local name of the schema component, such as 'element' or 'complexType'
+  attrs: Vec<(String, String)>,
+  children: Vec<Node>,
+  schema: usize
+}
+impl Node {
+  fn attr(&self, key: &str) -> Option<&str> {
+    self.attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+  }
+  fn repeats(&self) -> bool {
+    matches!(self.attr("maxOccurs"), Some(max) if max == "unbounded" || max.parse::<u32>().is_ok_and(|n| n > 1))
+  }
+}
+
+struct Schema {
+  target: Option<String>,
+  qualified: bool, // The elementFormDefault setting
+  prefixes: HashMap<String, String>
+}
+
+enum Kind<'x> {
+  Simple(Option<&'static str>), // Datatype, if other than text
+  Complex(&'x Node),
+  Geometry(bool) // Whether gml-to-ewkb supports it
+}
+
+#[derive(Default)]
+struct Content<'x> {
+  elements: Vec<(Option<&'x str>, &'x str, &'x Node, bool)>, // Namespace, name, declaration and whether it repeats
+  attributes: Vec<(String, Option<&'static str>)>,
+  text: Option<Option<&'static str>>
+}
+
+struct Xsd {
+  schemas: Vec<Schema>,
+  nodes: Vec<Node>,
+  globals: HashMap<(String, String), (usize, usize)>, // Kind of component and name to the index in nodes and its children
+  namespaces: RefCell<Vec<(String, String)>> // Prefixes and URIs used in the generated paths
+}
+impl Xsd {
+  fn load(&mut self, path: &Path, loaded: &mut HashSet<PathBuf>) {
+    let canonical = path.canonicalize().unwrap_or_else(|err| fatalerr!("Error: failed to open schema file '{}': {}", path.display(), err));
+    if !loaded.insert(canonical) { return; }
+    let index = self.schemas.len();
+    let root = parse(path, index);
+    self.schemas.push(Schema {
+      target: root.attr("targetNamespace").map(String::from),
+      qualified: root.attr("elementFormDefault") == Some("qualified"),
+      prefixes: root.attrs.iter().filter_map(|(key, uri)| match key.as_str() {
+        "xmlns" => Some((String::new(), uri.clone())),
+        key => key.strip_prefix("xmlns:").map(|prefix| (prefix.to_owned(), uri.clone()))
+      }).collect()
+    });
+    for child in &root.children {
+      if !["include", "import", "redefine"].contains(&child.name.as_str()) { continue; }
+      let Some(location) = child.attr("schemaLocation") else { continue };
+      if child.attr("namespace").is_some_and(|ns| ns.starts_with(GML_NS)) || location.contains("://") { continue; } // Only local schemas are loaded; GML is known
+      let file = path.parent().unwrap_or(Path::new(".")).join(location);
+      if file.exists() { self.load(&file, loaded); }
+      else { eprintln!("Warning: schema file '{}' referenced from {} not found; its types are treated as text", file.display(), path.display()); }
+    }
+    for (i, child) in root.children.iter().enumerate() {
+      if let Some(name) = child.attr("name") {
+        self.globals.entry((child.name.clone(), name.to_owned())).or_insert((self.nodes.len(), i));
+      }
+    }
+    self.nodes.push(root);
+  }
+  fn global(&self, kind: &str, name: &str) -> Option<&Node> {
+    self.globals.get(&(kind.to_owned(), name.to_owned())).map(|(node, child)| &self.nodes[*node].children[*child])
+  }
+  fn resolve<'a>(&self, context: &Node, qname: &'a str) -> (Option<&str>, &'a str) { // Namespace URI and local name of a type or element reference
+    let (prefix, local) = qname.split_once(':').unwrap_or(("", qname));
+    (self.schemas[context.schema].prefixes.get(prefix).map(String::as_str), local)
+  }
+  fn step(&self, namespace: Option<&str>, local: &str) -> String { // Path step for an element, with a prefix for its namespace
+    let Some(uri) = namespace else { return local.to_owned() };
+    let mut namespaces = self.namespaces.borrow_mut();
+    if let Some((prefix, _)) = namespaces.iter().find(|(_, u)| u == uri) { return format!("{}:{}", prefix, local); }
+    let mut prefix = self.schemas.iter().flat_map(|s| s.prefixes.iter()).find(|(p, u)| *u == uri && !p.is_empty()).map_or(String::from("ns"), |(p, _)| p.clone());
+    if namespaces.iter().any(|(p, _)| *p == prefix) { prefix = format!("ns{}", namespaces.len()+1); }
+    namespaces.push((prefix.clone(), uri.to_owned()));
+    format!("{}:{}", prefix, local)
+  }
+
+  fn kind<'x>(&'x self, decl: &'x Node) -> Kind<'x> {
+    if let Some(reference) = decl.attr("ref") { // Reference to an element outside of the loaded schemas
+      return match self.resolve(decl, reference) {
+        (Some(ns), local) if ns.starts_with(GML_NS) && is_geometry(local) => Kind::Geometry(is_ewkb_geometry(local)),
+        _ => Kind::Simple(None)
+      };
+    }
+    if let Some(typename) = decl.attr("type") { return self.named_type(decl, typename); }
+    for child in &decl.children {
+      match child.name.as_str() {
+        "complexType" => {
+          let mut content = Content::default();
+          self.content(child, false, &mut content);
+          let kinds = content.elements.iter().map(|(_, _, decl, _)| self.kind(decl)).collect::<Vec<Kind>>();
+          if !kinds.is_empty() && kinds.iter().all(|kind| matches!(kind, Kind::Geometry(_))) { return Kind::Geometry(kinds.iter().all(|kind| matches!(kind, Kind::Geometry(true)))); }
+          return Kind::Complex(child);
+        },
+        "simpleType" => return Kind::Simple(self.simple_type(child)),
+        _ => ()
+      }
+    }
+    Kind::Simple(None)
+  }
+  fn named_type(&self, context: &Node, qname: &str) -> Kind<'_> {
+    match self.resolve(context, qname) {
+      (Some(XSD_NS), local) => Kind::Simple(builtin_type(local)),
+      (Some(ns), local) if ns.starts_with(GML_NS) => match local.strip_suffix("PropertyType") {
+        Some(geometry) if is_geometry(geometry) => Kind::Geometry(is_ewkb_geometry(geometry)),
+        _ => Kind::Simple(None)
+      },
+      (_, local) => match (self.global("complexType", local), self.global("simpleType", local)) {
+        (Some(complex), _) => Kind::Complex(complex),
+        (None, Some(simple)) => Kind::Simple(self.simple_type(simple)),
+        (None, None) => {
+          eprintln!("Warning: type {} not found in the schema; treated as text", qname);
+          Kind::Simple(None)
+        }
+      }
+    }
+  }
+  fn simple_type(&self, node: &Node) -> Option<&'static str> {
+    let restriction = node.children.iter().find(|c| c.name == "restriction")?; // Lists and unions go into text columns
+    match restriction.attr("base") {
+      Some(base) => match self.named_type(restriction, base) {
+        Kind::Simple(datatype) => datatype,
+        _ => None
+      },
+      None => restriction.children.iter().find(|c| c.name == "simpleType").and_then(|c| self.simple_type(c))
+    }
+  }
+  fn content<'x>(&'x self, node: &'x Node, repeat: bool, content: &mut Content<'x>) {
+    for child in &node.children {
+      match child.name.as_str() {
+        "sequence" | "choice" | "all" => self.content(child, repeat || child.repeats(), content),
+        "group" => match child.attr("ref") {
+          Some(reference) => if let Some(group) = self.global("group", self.resolve(child, reference).1) { self.content(group, repeat || child.repeats(), content); },
+          None => self.content(child, repeat, content)
+        },
+        "attributeGroup" => match child.attr("ref") {
+          Some(reference) => if let Some(group) = self.global("attributeGroup", self.resolve(child, reference).1) { self.content(group, repeat, content); },
+          None => self.content(child, repeat, content)
+        },
+        "element" => {
+          let repeat = repeat || child.repeats();
+          match child.attr("ref") {
+            Some(reference) => {
+              let (ns, local) = self.resolve(child, reference);
+              match ns.is_some_and(|ns| ns.starts_with(GML_NS)) {
+                true => content.elements.push((ns, local, child, repeat)),
+                false => match self.global("element", local) {
+                  Some(decl) => content.elements.push((self.schemas[decl.schema].target.as_deref(), local, decl, repeat)),
+                  None => eprintln!("Warning: element {} not found in the schema; skipped", reference)
+                }
+              }
+            },
+            None => {
+              let name = child.attr("name").unwrap_or_default();
+              let qualified = match child.attr("form") {
+                Some(form) => form == "qualified",
+                None => self.schemas[child.schema].qualified
+              };
+              content.elements.push((if qualified { self.schemas[child.schema].target.as_deref() } else { None }, name, child, repeat));
+            }
+          }
+        },
+        "attribute" => {
+          if child.attr("use") == Some("prohibited") { continue; }
+          let (name, decl) = match child.attr("ref") {
+            Some(reference) => (reference, self.global("attribute", self.resolve(child, reference).1).unwrap_or(child)),
+            None => (child.attr("name").unwrap_or_default(), child)
+          };
+          let datatype = match decl.attr("type") {
+            Some(typename) => match self.named_type(decl, typename) {
+              Kind::Simple(datatype) => datatype,
+              _ => None
+            },
+            None => decl.children.iter().find(|c| c.name == "simpleType").and_then(|c| self.simple_type(c))
+          };
+          content.attributes.push((name.to_owned(), datatype));
+        },
+        "complexContent" | "restriction" => self.content(child, repeat, content),
+        "extension" => { // Extension of a complex type; the base content comes first
+          if let Some(Kind::Complex(base)) = child.attr("base").map(|base| self.named_type(child, base)) { self.content(base, repeat, content); }
+          self.content(child, repeat, content);
+        },
+        "simpleContent" => for derivation in &child.children {
+          match derivation.attr("base").map(|base| self.named_type(derivation, base)) {
+            Some(Kind::Complex(base)) => self.content(base, repeat, content),
+            Some(Kind::Simple(datatype)) => content.text = Some(datatype),
+            _ => content.text = Some(None)
+          }
+          self.content(derivation, repeat, content); // The attributes
+        },
+        _ => ()
+      }
+    }
+    if node.attr("mixed") == Some("true") && content.text.is_none() { content.text = Some(None); }
+  }
+
+  fn table_columns(&self, name: &str, content: &Content, depth: usize) -> Vec<Yaml> {
+    let mut cols = Vec::new();
+    let mut names = HashSet::new();
+    for (attr, datatype) in &content.attributes {
+      cols.push(Yaml::Hash(column(unique_name(attr, "", &mut names), "/", Some(attr), *datatype)));
+    }
+    if let Some(datatype) = content.text {
+      cols.push(Yaml::Hash(column(unique_name(name, "", &mut names), "/", None, datatype)));
+    }
+    self.child_columns(&content.elements, "", &mut cols, &mut names, depth);
+    let ids = cols.iter().enumerate().filter(|(_, col)| col["file"].is_badvalue()).filter_map(|(i, col)| match (col["attr"].as_str(), col["path"].as_str()) {
+      (Some(attr), Some("/")) => id_rank(attr),
+      (None, Some(path)) if path != "/" && !path.contains('/') => id_rank(path),
+      _ => None
+    }.map(|rank| (rank, i)));
+    match ids.min() { // The first column is the key for the subtables
+      Some((_, i)) => {
+        let col = cols.remove(i);
+        cols.insert(0, col);
+      },
+      None if cols.iter().any(|col| !col["file"].is_badvalue()) => { // Numbered rows rather than an arbitrary attribute
+        let mut col = Hash::new();
+        col.insert(Yaml::from_str("name"), Yaml::String(unique_name("id", "", &mut names)));
+        col.insert(Yaml::from_str("seri"), Yaml::Boolean(true));
+        cols.insert(0, Yaml::Hash(col));
+      },
+      None => ()
+    }
+    key_column(&mut cols, &mut names);
+    cols
+  }
+  fn child_columns(&self, elements: &[(Option<&str>, &str, &Node, bool)], prefix: &str, cols: &mut Vec<Yaml>, names: &mut HashSet<String>, depth: usize) {
+    for (ns, local, decl, repeat) in elements {
+      let step = self.step(*ns, local);
+      let path = if prefix.is_empty() { step } else { format!("{}/{}", prefix, step) };
+      if depth > 10 {
+        eprintln!("Warning: element {} is nested too deep (recursive schema?); not included", path);
+        continue;
+      }
+      match self.kind(decl) {
+        Kind::Geometry(supported) => {
+          let mut col = column(unique_name(local, &path, names), &path, None, None);
+          col.insert(Yaml::from_str("conv"), Yaml::from_str(if supported { "gml-to-ewkb" } else { "xml-to-text" })); // Other geometries are kept as GML
+          cols.push(Yaml::Hash(col));
+        },
+        Kind::Simple(datatype) => {
+          let name = unique_name(local, &path, names);
+          let mut col = column(name.clone(), &path, None, datatype);
+          if *repeat { col.insert(Yaml::from_str("file"), Yaml::String(format!("{}.dump", name))); } // Single column subtable
+          cols.push(Yaml::Hash(col));
+        },
+        Kind::Complex(complex) => {
+          let mut content = Content::default();
+          self.content(complex, false, &mut content);
+          if *repeat { // One-to-many subtable
+            let name = unique_name(local, &path, names);
+            let single = content.elements.is_empty() && content.attributes.is_empty();
+            let mut col = column(name.clone(), &path, None, if single { content.text.flatten() } else { None });
+            col.insert(Yaml::from_str("file"), Yaml::String(format!("{}.dump", name)));
+            if !single { col.insert(Yaml::from_str("cols"), Yaml::Array(self.table_columns(local, &content, depth+1))); }
+            cols.push(Yaml::Hash(col));
+            continue;
+          }
+          for (attr, datatype) in &content.attributes {
+            cols.push(Yaml::Hash(column(unique_name(&format!("{}_{}", local, local_name(attr)), &format!("{}/{}", path, attr), names), &path, Some(attr), *datatype)));
+          }
+          if let Some(datatype) = content.text {
+            cols.push(Yaml::Hash(column(unique_name(local, &path, names), &path, None, datatype)));
+          }
+          self.child_columns(&content.elements, &path, cols, names, depth+1);
+        }
+      }
+    }
+  }
+  fn find_row(&self, row: &str) -> Option<(String, &Node)> { // Searches the content of the root elements for the row element
+    let referenced: HashSet<&str> = self.nodes.iter().flat_map(references).collect();
+    let mut roots: Vec<(&String, &Node)> = self.globals.iter().filter_map(|((kind, name), _)| {
+      let decl = self.global(kind, name)?;
+      (kind == "element" && !referenced.contains(name.as_str()) && decl.attr("abstract") != Some("true")).then_some((name, decl))
+    }).collect();
+    roots.sort_by_key(|(name, _)| *name);
+    let mut level: Vec<(String, &Node)> = roots.into_iter().map(|(name, decl)| (format!("/{}", self.step(self.schemas[decl.schema].target.as_deref(), name)), decl)).collect();
+    for _ in 0..12 {
+      if let Some((path, decl)) = level.iter().find(|(path, _)| *path == row || (!row.contains('/') && local_name(path.rsplit('/').next().unwrap()) == row)) {
+        return Some((path.clone(), *decl));
+      }
+      let mut next = Vec::new();
+      for (path, decl) in &level {
+        let Kind::Complex(complex) = self.kind(decl) else { continue };
+        let mut content = Content::default();
+        self.content(complex, false, &mut content);
+        for (ns, local, decl, _) in content.elements {
+          if ns.is_some_and(|ns| ns.starts_with(GML_NS)) { continue; }
+          next.push((format!("{}/{}", path, self.step(ns, local)), decl));
+          for (kind, name) in self.globals.keys() { // Elements that may take the place of this one
+            let Some(member) = self.global(kind, name) else { continue };
+            if kind == "element" && member.attr("substitutionGroup").is_some_and(|group| local_name(group) == local) {
+              next.push((format!("{}/{}", path, self.step(self.schemas[member.schema].target.as_deref(), name)), member));
+            }
+          }
+        }
+        if next.len() > 10000 { break; }
+      }
+      level = next;
+    }
+    None
+  }
+}
+
+fn references(node: &Node) -> Vec<&str> {
+  let mut refs: Vec<&str> = node.children.iter().flat_map(references).collect();
+  if node.name == "element" {
+    refs.extend(node.attr("ref").map(local_name));
+    if node.attr("substitutionGroup").is_some() { refs.extend(node.attr("name")); } // Substitutes are no root elements either
+  }
+  refs
+}
+
+fn declares(node: &Node, name: &str) -> bool {
+  (node.name == "element" && node.attr("name") == Some(name)) || node.children.iter().any(|child| declares(child, name))
+}
+
+fn parse(path: &Path, schema: usize) -> Node {
+  let file = File::open(path).unwrap_or_else(|err| fatalerr!("Error: failed to open schema file '{}': {}", path.display(), err));
+  let mut reader = xml_reader(Box::new(BufReader::new(file)));
+  let mut stack = vec![Node::default()];
+  let mut buf = Vec::new();
+  loop {
+    match reader.read_event_into(&mut buf).unwrap_or_else(|e| fatalerr!("Error: failed to parse schema {} at position {}: {}", path.display(), reader.buffer_position(), e)) {
+      Event::Start(e) => {
+        let name = String::from_utf8_lossy(e.local_name().as_ref()).into_owned();
+        let attrs = e.attributes().flatten().map(|attr| (
+          String::from_utf8_lossy(attr.key.as_ref()).into_owned(),
+          attr.decode_and_unescape_value(reader.decoder()).unwrap_or_default().into_owned()
+        )).collect();
+        stack.push(Node { name, attrs, children: Vec::new(), schema });
+      },
+      Event::End(_) => {
+        let node = stack.pop().unwrap();
+        stack.last_mut().unwrap().children.push(node);
+      },
+      Event::Eof => break,
+      _ => ()
+    }
+    buf.clear();
+  }
+  stack.pop().unwrap().children.into_iter().find(|n| n.name == "schema").unwrap_or_else(|| fatalerr!("Error: {} is not an XML Schema file", path.display()))
+}
+
+fn builtin_type(name: &str) -> Option<&'static str> {
+  match name {
+    "short" | "byte" | "unsignedByte" => Some("smallint"),
+    "int" | "unsignedShort" => Some("integer"),
+    "long" | "integer" | "unsignedInt" | "nonNegativeInteger" | "positiveInteger" | "negativeInteger" | "nonPositiveInteger" => Some("bigint"),
+    "unsignedLong" | "decimal" => Some("numeric"),
+    "float" => Some("real"),
+    "double" => Some("double precision"),
+    "boolean" => Some("boolean"),
+    "date" => Some("date"),
+    "dateTime" => Some("timestamptz"),
+    "time" => Some("time"),
+    "duration" => Some("interval"),
+    _ => None
+  }
+}
+
+fn is_geometry(name: &str) -> bool { // GML geometry elements and the corresponding property types
+  ["Geometry", "Point", "Curve", "LineString", "Surface", "Polygon", "Primitive", "Solid"].iter().any(|g| name.contains(g))
+}
+fn id_rank(name: &str) -> Option<u8> { // Whether an attribute or element looks like an identifier of its row, best first
+  let name = local_name(name);
+  if name.eq_ignore_ascii_case("id") { Some(0) }
+  else if name.ends_with("Id") || name.ends_with("ID") || name.ends_with("_id") { Some(1) }
+  else { None }
+}
+fn is_ewkb_geometry(name: &str) -> bool { // The ones gml-to-ewkb can convert, as in scan
+  matches!(name, "Point" | "LineString" | "Polygon" | "MultiPolygon")
+}
+
+pub fn xsd(schema: &str, row: &str) {
+  let mut xsd = Xsd { schemas: Vec::new(), nodes: Vec::new(), globals: HashMap::new(), namespaces: RefCell::new(Vec::new()) };
+  xsd.load(Path::new(schema), &mut HashSet::new());
+  let (rowpath, decl) = match xsd.find_row(row) {
+    Some(found) => found,
+    None if row.contains('/') => match xsd.global("element", local_name(row.rsplit('/').next().unwrap())) {
+      Some(decl) => (row.trim_end_matches('/').to_owned(), decl),
+      None => fatalerr!("Error: element {} not found in schema {}", row, schema)
+    },
+    None if !xsd.nodes.iter().any(|node| declares(node, row)) => fatalerr!("Error: element {} not found in schema {}", row, schema),
+    None => fatalerr!("Error: element {} not found below the root elements of schema {}; pass its full path instead (such as /Root/Items/{})", row, schema, row)
+  };
+  let name = local_name(rowpath.rsplit('/').next().unwrap());
+  let mut content = Content::default();
+  match xsd.kind(decl) {
+    Kind::Complex(complex) => xsd.content(complex, false, &mut content),
+    Kind::Simple(datatype) => content.text = Some(datatype),
+    Kind::Geometry(_) => fatalerr!("Error: element {} is a geometry; choose the element that contains it as the row element", row)
+  }
+  eprintln!("Info: using {} as the main table", rowpath);
+  let cols = xsd.table_columns(name, &content, 0);
+
+  let tabname = colname(name);
+  let mut config = Hash::new();
+  config.insert(Yaml::from_str("name"), Yaml::String(tabname.clone()));
+  config.insert(Yaml::from_str("path"), Yaml::String(rowpath));
+  config.insert(Yaml::from_str("file"), Yaml::String(format!("{}.dump", tabname)));
+  config.insert(Yaml::from_str("emit"), Yaml::from_str("create_table"));
+  let namespaces = xsd.namespaces.borrow();
+  if !namespaces.is_empty() {
+    config.insert(Yaml::from_str("namespaces"), Yaml::Hash(namespaces.iter().map(|(prefix, uri)| (Yaml::String(prefix.clone()), Yaml::String(uri.clone()))).collect()));
+  }
+  config.insert(Yaml::from_str("cols"), Yaml::Array(cols));
+  print_config(&Yaml::Hash(config), &format!("schema {}", schema));
+}
@@ -0,0 +1,104 @@
+// Checkpoint and --resume round trip: a run killed after a checkpoint and resumed gives the same output as an uninterrupted one
+
+#![cfg(unix)]
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const BIN: &str = env!("CARGO_BIN_EXE_xml-to-postgres");
+const CONFIG: &str = "
+hush: info
+spill: 50
+checkpoint: cp
+interval: 1
+name: item
+path: /root/item
+file: item.dump
+cols:
+  - name: id
+    seri: true
+    hash: name
+  - name: name
+    path: name
+  - name: city
+    path: city
+    norm: city.dump
+    hash: true
+  - name: kind
+    path: kind
+    norm: kind.dump
+  - name: tag
+    path: tag
+    file: tag.dump
+    cols:
+      - name: tag
+        path: /
+";
+const OUTPUTS: [&str; 4] = ["item.dump", "city.dump", "kind.dump", "tag.dump"];
+
+fn row(i: usize) -> String {
+  format!("<item><name>n{}\\x</name><city>c{}</city><kind>k{}</kind><tag>t{}</tag><tag>u{}</tag></item>\n", i, i%500, i%30, i%7, i)
+}
+
+fn setup(name: &str) -> PathBuf {
+  let dir = env::temp_dir().join(format!("xml-to-postgres-test-{}-{}", name, std::process::id()));
+  let _ = fs::remove_dir_all(&dir);
+  fs::create_dir(&dir).unwrap();
+  fs::write(dir.join("c.yml"), CONFIG).unwrap();
+  dir
+}
+
+fn run(dir: &Path, args: &[&str]) {
+  let status = Command::new(BIN).args(args).current_dir(dir).status().unwrap();
+  assert!(status.success(), "{:?} failed", args);
+}
+
+#[test]
+fn resume_after_kill() {
+  let rows = 5000;
+  let input = format!("<root>\n{}</root>\n", (0..rows).map(row).collect::<String>());
+
+  let full = setup("full");
+  fs::write(full.join("in.xml"), &input).unwrap();
+  run(&full, &["c.yml", "in.xml"]);
+
+  // The input is fed through a pipe until a checkpoint is written, then the run is killed
+  let part = setup("part");
+  assert!(Command::new("mkfifo").arg(part.join("in.xml")).status().unwrap().success());
+  let mut child = Command::new(BIN).args(["c.yml", "in.xml"]).current_dir(&part).spawn().unwrap();
+  let mut pipe = File::create(part.join("in.xml")).unwrap();
+  let mut lines = input.split_inclusive('\n');
+  for line in lines.by_ref().take(rows/2) { pipe.write_all(line.as_bytes()).unwrap(); }
+  let start = Instant::now();
+  let mut checkpoint = String::new();
+  while checkpoint.is_empty() {
+    assert!(start.elapsed() < Duration::from_secs(60), "no checkpoint written");
+    if let Some(line) = lines.next() { pipe.write_all(line.as_bytes()).unwrap(); }
+    else { panic!("input exhausted before a checkpoint was written"); }
+    thread::sleep(Duration::from_millis(5));
+    checkpoint = fs::read_to_string(part.join("cp")).unwrap_or_default();
+  }
+  child.kill().unwrap();
+  child.wait().unwrap();
+  drop(pipe);
+  assert!(checkpoint.lines().filter(|line| line.starts_with("index\t")).count() >= 3, "spilled domain and ids seen are not in index copies:\n{}", checkpoint);
+  assert!(checkpoint.contains("\nvalues\n"), "the kind domain should not have spilled:\n{}", checkpoint);
+  assert!(fs::metadata(part.join("item.dump")).unwrap().len() < fs::metadata(full.join("item.dump")).unwrap().len());
+
+  fs::remove_file(part.join("in.xml")).unwrap();
+  fs::write(part.join("in.xml"), &input).unwrap();
+  run(&part, &["--resume", "c.yml", "in.xml"]);
+  for output in OUTPUTS {
+    assert!(fs::read(full.join(output)).unwrap() == fs::read(part.join(output)).unwrap(), "{} differs after resume", output);
+  }
+  assert!(!part.join("cp").exists());
+  assert!(fs::read_dir(&part).unwrap().all(|entry| !entry.unwrap().file_name().to_string_lossy().starts_with("cp.")), "index copies left behind");
+
+  fs::remove_dir_all(full).unwrap();
+  fs::remove_dir_all(part).unwrap();
+}